@@ -0,0 +1,216 @@
+use anyhow::Context;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::Method,
+    middleware::Next,
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use reqwest::{header::AUTHORIZATION, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use crate::consts::{API_KEYS, SERVICE_SECRET_TOKEN};
+use crate::error::Error;
+
+/// What a caller is allowed to do
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `/duplicate`, `/duplicate_raw/*` and updating the metadata of videos
+    Duplicate,
+    /// `/hls/*`
+    Hls,
+    /// Moving videos between tiers
+    Move,
+    /// Looking things up, e.g. jobs
+    Read,
+    /// Deleting videos and erasing publishers
+    Delete,
+    /// Everything, including routes without a scope of their own
+    Admin,
+}
+
+impl Scope {
+    /// The scope a route requires. Routes missing here are reserved to admins.
+    fn required_for(method: &Method, path: &str) -> Self {
+        match path {
+            "/duplicate"
+            | "/duplicate_raw/upload"
+            | "/duplicate_raw/finalize"
+            | "/videos/{publisher_user_id}/{video_id}/metadata" => Scope::Duplicate,
+            "/hls/duplicate" => Scope::Hls,
+            "/move-to-nsfw" | "/move-to-sfw" => Scope::Move,
+            "/videos/{publisher_user_id}/{video_id}" | "/publishers/{publisher_user_id}"
+                if method == Method::DELETE =>
+            {
+                Scope::Delete
+            }
+            "/jobs/{id}"
+            | "/videos/{publisher_user_id}/{video_id}"
+            | "/publishers/{publisher_user_id}/videos"
+            | "/publishers/{publisher_user_id}/export" => Scope::Read,
+            _ => Scope::Admin,
+        }
+    }
+}
+
+/// The authenticated caller of a request, available as a request extension
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+impl Caller {
+    pub fn can(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// An API key as configured
+#[derive(Deserialize)]
+struct KeyConfig {
+    /// Name of the caller, shared by all keys of the caller
+    name: String,
+    key: String,
+    scopes: BTreeSet<Scope>,
+    /// The key is accepted from then on. Together with `expires_at` of the key
+    /// it replaces, this lets an old and a new key overlap during rotation.
+    #[serde(default)]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+struct ApiKey {
+    /// Keys are compared by digest, so comparisons take the same time for every key length
+    digest: [u8; 32],
+    not_before: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    caller: Caller,
+}
+
+impl ApiKey {
+    fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|at| at <= now) && self.expires_at.is_none_or(|at| now < at)
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Every API key the service accepts
+#[derive(Clone)]
+pub struct Registry {
+    keys: Arc<Vec<ApiKey>>,
+}
+
+impl Registry {
+    /// Load the keys configured in `API_KEYS`/`API_KEYS_FILE`, plus the legacy
+    /// `SERVICE_SECRET_TOKEN` as an admin key
+    pub fn load() -> anyhow::Result<Self> {
+        let configs: Vec<KeyConfig> = match API_KEYS.as_deref() {
+            Some(keys) => serde_json::from_str(keys).context("Couldn't parse API keys")?,
+            None => vec![],
+        };
+
+        let mut keys: Vec<ApiKey> = configs
+            .into_iter()
+            .map(|config| ApiKey {
+                digest: digest(&config.key),
+                not_before: config.not_before,
+                expires_at: config.expires_at,
+                caller: Caller {
+                    name: config.name,
+                    scopes: config.scopes,
+                },
+            })
+            .collect();
+
+        if let Some(token) = SERVICE_SECRET_TOKEN.as_deref() {
+            keys.push(ApiKey {
+                digest: digest(token),
+                not_before: None,
+                expires_at: None,
+                caller: Caller {
+                    name: "legacy".into(),
+                    scopes: BTreeSet::from([Scope::Admin]),
+                },
+            });
+        }
+
+        anyhow::ensure!(
+            !keys.is_empty(),
+            "No API keys configured, set API_KEYS, API_KEYS_FILE or SERVICE_SECRET_TOKEN"
+        );
+        println!("Accepting {} API keys", keys.len());
+
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    /// The caller a key belongs to, if the key is currently valid
+    fn authenticate(&self, key: &str) -> Option<&Caller> {
+        let digest = digest(key);
+        let now = Utc::now();
+
+        // Check every key, so the time taken doesn't depend on which one matched
+        self.keys
+            .iter()
+            .filter(|api_key| bool::from(api_key.digest.ct_eq(&digest)))
+            .fold(None, |found, api_key| {
+                found.or((api_key.is_valid_at(now)).then_some(&api_key.caller))
+            })
+    }
+}
+
+/// Authenticate the caller by its bearer token and check that it may use the route
+pub async fn authorize(
+    State(registry): State<Registry>,
+    path: MatchedPath,
+    mut request: Request,
+    next: Next,
+) -> Result<impl IntoResponse, Error> {
+    let auth = request
+        .headers()
+        .get(AUTHORIZATION)
+        .ok_or(Error::Unauthorized)?;
+    let auth = auth.to_str().map_err(|_| Error::InvalidInput {
+        status: StatusCode::BAD_REQUEST,
+        detail: "The Authorization header isn't valid ascii".into(),
+    })?;
+    let key = auth.strip_prefix("Bearer ").ok_or(Error::Unauthorized)?;
+
+    let caller = registry
+        .authenticate(key)
+        .ok_or(Error::Unauthorized)?
+        .clone();
+
+    let scope = Scope::required_for(request.method(), path.as_str());
+    if !caller.can(scope) {
+        eprintln!(
+            "{caller} lacks the {scope:?} scope for {} {}",
+            request.method(),
+            path.as_str()
+        );
+        return Err(Error::Forbidden);
+    }
+
+    println!("{} {} by {caller}", request.method(), request.uri().path());
+    request.extensions_mut().insert(caller);
+
+    Ok(next.run(request).await)
+}
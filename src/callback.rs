@@ -0,0 +1,142 @@
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::consts::CALLBACK_SECRET;
+use crate::error::ErrorCode;
+use crate::jobs::{Job, JobState};
+use crate::store::{integrity::SHA256_METADATA_KEY, progress::BackendProgress, Storage};
+
+/// Header carrying the unix timestamp the callback was signed at
+pub const TIMESTAMP_HEADER: &str = "X-Callback-Timestamp";
+/// Header carrying `sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`
+pub const SIGNATURE_HEADER: &str = "X-Callback-Signature";
+
+/// Redirects are not followed, as only the callback url itself was checked
+/// not to point at an internal host
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("callback client to build")
+});
+
+/// Delivery state of a job's callback
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CallbackStatus {
+    pub attempts: u32,
+    pub delivered: bool,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+}
+
+/// Body POSTed to a job's callback url once the job finished
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub job_id: String,
+    /// `duplicate`, `finalize`, `move` or `move_to_sfw`
+    pub operation: &'static str,
+    pub state: JobState,
+    pub attempts: u32,
+    /// The objects written by the job, as found in storage afterwards
+    pub objects: Vec<ReportedObject>,
+    /// Per-backend progress of the last attempt
+    pub backends: BTreeMap<String, BackendProgress>,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReportedObject {
+    pub key: String,
+    pub is_nsfw: bool,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    /// Whether each backend of the tier holds the object
+    pub backends: BTreeMap<&'static str, bool>,
+}
+
+impl Report {
+    pub async fn of(job: &Job, storage: &Storage) -> Self {
+        let (is_nsfw, keys) = job.request.objects(storage).await;
+        let tier = storage.tier(is_nsfw);
+
+        let mut objects = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut object = ReportedObject {
+                key,
+                is_nsfw,
+                size: None,
+                sha256: None,
+                backends: BTreeMap::new(),
+            };
+            for (backend, info) in tier.head_all(&object.key).await {
+                let info = info
+                    .inspect_err(|e| eprintln!("Couldn't check {} on {backend}: {e}", object.key))
+                    .ok()
+                    .flatten();
+                object.backends.insert(backend, info.is_some());
+                if let Some(info) = info {
+                    object.size = object.size.or(info.size);
+                    object.sha256 = object
+                        .sha256
+                        .or_else(|| info.metadata.get(SHA256_METADATA_KEY).cloned());
+                }
+            }
+            objects.push(object);
+        }
+
+        Self {
+            job_id: job.id.clone(),
+            operation: job.request.operation(),
+            state: job.state,
+            attempts: job.attempts,
+            objects,
+            backends: job.progress.clone(),
+            result: job.result.clone(),
+            error: job.error.clone(),
+            error_code: job.error_code,
+        }
+    }
+}
+
+/// Signature of a callback body sent at `timestamp`
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac to accept keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POST a report to `url` once, signed with `CALLBACK_SECRET`
+pub async fn send(url: &str, report: &Report) -> Result<(), String> {
+    let body = serde_json::to_vec(report).expect("reports to be serializable");
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let mut request = HTTP
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp);
+    request = request.header(SIGNATURE_HEADER, sign(&CALLBACK_SECRET, timestamp, &body));
+
+    let resp = request.body(body).send().await.map_err(|e| e.to_string())?;
+    if resp.status().is_redirection() {
+        return Err(format!(
+            "callback returned {}, redirects are not followed",
+            resp.status()
+        ));
+    }
+    if !resp.status().is_success() {
+        return Err(format!("callback returned {}", resp.status()));
+    }
+
+    Ok(())
+}
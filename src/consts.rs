@@ -1,4 +1,6 @@
 use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 // Storj configuration
 pub static YRAL_VIDEOS: Lazy<String> = Lazy::new(|| {
@@ -42,9 +44,144 @@ pub static HETZNER_S3_SECRET_KEY: Lazy<String> = Lazy::new(|| {
 pub static HETZNER_S3_REGION: Lazy<String> =
     Lazy::new(|| std::env::var("HETZNER_S3_REGION").unwrap_or_else(|_| "eu-central".to_string()));
 
-pub static SERVICE_SECRET_TOKEN: Lazy<String> = Lazy::new(|| {
-    format!(
-        "Bearer {}",
-        std::env::var("SERVICE_SECRET_TOKEN").expect("A shared secret to be present")
-    )
+// Multipart uploads to Hetzner S3
+pub static HETZNER_S3_MULTIPART_THRESHOLD_MB: Lazy<u64> = Lazy::new(|| {
+    const FALLBACK: u64 = 64;
+    std::env::var("HETZNER_S3_MULTIPART_THRESHOLD_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+});
+pub static HETZNER_S3_MULTIPART_PART_SIZE_MB: Lazy<u64> = Lazy::new(|| {
+    const FALLBACK: u64 = 16;
+    // S3 rejects parts smaller than 5MB (except the last one)
+    std::env::var("HETZNER_S3_MULTIPART_PART_SIZE_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+        .max(5)
+});
+pub static HETZNER_S3_MULTIPART_CONCURRENCY: Lazy<usize> = Lazy::new(|| {
+    const FALLBACK: usize = 4;
+    std::env::var("HETZNER_S3_MULTIPART_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+        .max(1)
+});
+
+// Retries of storage operations
+/// Attempts of a storage operation in total, including the first one
+pub static STORE_RETRY_MAX_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    const FALLBACK: u32 = 3;
+    std::env::var("STORE_RETRY_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+        .max(1)
+});
+/// Upper bound of the delay before the first retry, doubled for every further retry
+pub static STORE_RETRY_BASE_DELAY_MS: Lazy<u64> = Lazy::new(|| {
+    const FALLBACK: u64 = 500;
+    std::env::var("STORE_RETRY_BASE_DELAY_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+});
+pub static STORE_RETRY_MAX_DELAY_MS: Lazy<u64> = Lazy::new(|| {
+    const FALLBACK: u64 = 10_000;
+    std::env::var("STORE_RETRY_MAX_DELAY_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+});
+
+// Video sources
+pub static CLOUDFLARE_STREAM_BASE_URL: Lazy<String> = Lazy::new(|| {
+    const FALLBACK_CUSTOMER_CODE: &str = "2p3jflss4r4hmpnz";
+    std::env::var("CLOUDFLARE_STREAM_BASE_URL").unwrap_or_else(|_| {
+        let customer_code = std::env::var("CLOUDFLARE_STREAM_CUSTOMER_CODE")
+            .unwrap_or_else(|_| FALLBACK_CUSTOMER_CODE.into());
+        format!("https://customer-{customer_code}.cloudflarestream.com")
+    })
+});
+pub static CLOUDFLARE_STREAM_API_TOKEN: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("CLOUDFLARE_STREAM_API_TOKEN").ok());
+/// Allow plain http url sources on internal hosts, meant for pointing at a local stand-in when testing
+pub static ALLOW_HTTP_SOURCES: Lazy<bool> = Lazy::new(|| {
+    std::env::var("ALLOW_HTTP_SOURCES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+});
+
+/// Number of jobs worked on concurrently
+pub static JOB_WORKERS: Lazy<usize> = Lazy::new(|| {
+    const FALLBACK: usize = 2;
+    std::env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+});
+/// Directory jobs are persisted in, so they survive restarts.
+/// Defaults to the systemd state directory if there is one.
+pub static JOB_STORE_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    std::env::var("JOB_STORE_DIR")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("STATE_DIRECTORY").map(|dir| Path::new(&dir).join("jobs")))
+        .unwrap_or_else(|_| PathBuf::from("jobs"))
+});
+/// Number of times a job is attempted before it is marked as failed
+pub static JOB_MAX_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    const FALLBACK: u32 = 3;
+    std::env::var("JOB_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+        .max(1)
+});
+/// Number of times a job repairing backends that missed writes is attempted,
+/// higher than [`JOB_MAX_ATTEMPTS`] to outlast longer backend outages
+pub static REPAIR_JOB_MAX_ATTEMPTS: Lazy<u32> = Lazy::new(|| {
+    const FALLBACK: u32 = 12;
+    std::env::var("REPAIR_JOB_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK)
+        .max(1)
+});
+
+/// How long responses to requests with an Idempotency-Key are replayed
+pub static IDEMPOTENCY_TTL: Lazy<Duration> = Lazy::new(|| {
+    const FALLBACK_HOURS: u64 = 24;
+    let hours = std::env::var("IDEMPOTENCY_TTL_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(FALLBACK_HOURS);
+    Duration::from_secs(hours * 60 * 60)
+});
+
+// Callbacks
+/// Secret the callbacks sent on job completion and erasure receipts are signed with
+pub static CALLBACK_SECRET: Lazy<String> = Lazy::new(|| {
+    std::env::var("CALLBACK_SECRET").expect("Callback secret to be present: CALLBACK_SECRET")
+});
+
+// Authorization
+/// Legacy shared secret, accepted as a key with the admin scope
+pub static SERVICE_SECRET_TOKEN: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("SERVICE_SECRET_TOKEN")
+        .inspect_err(|err| {
+            println!("Not accepting a legacy shared secret because SERVICE_SECRET_TOKEN is {err}")
+        })
+        .ok()
+});
+/// Json list of API keys, given inline via API_KEYS or as a file via API_KEYS_FILE
+pub static API_KEYS: Lazy<Option<String>> = Lazy::new(|| {
+    if let Ok(keys) = std::env::var("API_KEYS") {
+        return Some(keys);
+    }
+    let path = std::env::var("API_KEYS_FILE").ok()?;
+    let keys = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("API keys file {path} to be readable: {err}"));
+    Some(keys)
 });
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::callback;
+use crate::consts::CALLBACK_SECRET;
+use crate::store::{self, Progress, Storage, StoreError};
+
+/// What an erasure deleted from one backend
+#[derive(Serialize, Debug)]
+pub struct ErasedBackend {
+    /// Objects deleted by the attempt that completed the erasure
+    pub deleted: usize,
+    /// Hex SHA-256 of the keys of those objects, sorted and joined by newlines
+    pub keys_sha256: String,
+}
+
+/// Record of everything of a publisher being erased from storage
+#[derive(Serialize, Debug)]
+pub struct Receipt {
+    pub publisher_user_id: String,
+    /// Videos whose HLS trees were erased along with them
+    pub video_ids: BTreeSet<String>,
+    /// Backend name to what was deleted from it
+    pub backends: BTreeMap<&'static str, ErasedBackend>,
+    /// When the backends were found to hold nothing of the publisher anymore
+    pub erased_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SignedReceipt {
+    pub receipt: Receipt,
+    /// `sha256=<hex HMAC-SHA256 of "{erased_at as unix timestamp}.{receipt as compact json}">`,
+    /// keyed with `CALLBACK_SECRET`
+    pub signature: String,
+}
+
+impl SignedReceipt {
+    fn of(receipt: Receipt) -> Self {
+        // Signed as it ends up in the job result, where it is kept as a json value
+        let value = serde_json::to_value(&receipt).expect("receipts to be serializable");
+        let body = serde_json::to_vec(&value).expect("json values to be serializable");
+        let signature = callback::sign(&CALLBACK_SECRET, receipt.erased_at.timestamp(), &body);
+        Self { receipt, signature }
+    }
+}
+
+/// Backend name to the keys it lists under each of `prefixes`, across both tiers
+async fn list_all(
+    storage: &Storage,
+    prefixes: &[String],
+) -> Result<BTreeMap<&'static str, BTreeSet<String>>, StoreError> {
+    let mut listed: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+    for tier in [&storage.sfw, &storage.nsfw] {
+        for prefix in prefixes {
+            for (backend, objects) in tier.list_each(prefix).await? {
+                let keys = listed.entry(backend).or_default();
+                keys.extend(objects.into_iter().map(|info| info.key));
+            }
+        }
+    }
+    Ok(listed)
+}
+
+/// Delete the objects listed on each backend, one tier at a time
+async fn delete_listed(
+    storage: &Storage,
+    listed: &BTreeMap<&'static str, BTreeSet<String>>,
+    progress: &Progress,
+) -> Result<(), StoreError> {
+    for tier in [&storage.sfw, &storage.nsfw] {
+        for backend in tier.backends() {
+            let Some(keys) = listed.get(backend).filter(|keys| !keys.is_empty()) else {
+                continue;
+            };
+            let keys: Vec<String> = keys.iter().cloned().collect();
+            tier.only(&[backend])
+                .tracked(progress)
+                .delete_many(&keys)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete everything a publisher has stored: every object under its
+/// directory key on all backends, and the HLS trees of its videos.
+///
+/// The HLS trees go first, as they are only found through the videos. Once
+/// done, the backends are listed again to make sure nothing is left, and the
+/// result is summed up in a receipt signed like callbacks are.
+pub async fn erase(
+    storage: &Storage,
+    publisher_user_id: &str,
+    progress: &Progress,
+) -> Result<SignedReceipt, StoreError> {
+    let prefix = format!("{publisher_user_id}/");
+    let owned = list_all(storage, std::slice::from_ref(&prefix)).await?;
+
+    let video_ids: BTreeSet<String> = owned
+        .values()
+        .flatten()
+        .filter_map(|key| store::video_id_of(publisher_user_id, key))
+        .map(str::to_string)
+        .collect();
+    let hls_prefixes: Vec<String> = video_ids
+        .iter()
+        .map(|video_id| store::hls_key(video_id, ""))
+        .collect();
+    let hls = list_all(storage, &hls_prefixes).await?;
+
+    delete_listed(storage, &hls, progress).await?;
+    delete_listed(storage, &owned, progress).await?;
+
+    let mut prefixes = hls_prefixes;
+    prefixes.push(prefix);
+    let left = list_all(storage, &prefixes)
+        .await?
+        .into_values()
+        .flatten()
+        .count();
+    if left > 0 {
+        return Err(StoreError::Integrity(format!(
+            "{left} objects of {publisher_user_id} are left after erasing them"
+        )));
+    }
+
+    let mut backends = BTreeMap::new();
+    for backend in storage
+        .sfw
+        .backends()
+        .into_iter()
+        .chain(storage.nsfw.backends())
+    {
+        let keys: BTreeSet<&String> = [&hls, &owned]
+            .into_iter()
+            .filter_map(|listed| listed.get(backend))
+            .flatten()
+            .collect();
+        let joined = keys
+            .iter()
+            .map(|key| key.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        backends.insert(
+            backend,
+            ErasedBackend {
+                deleted: keys.len(),
+                keys_sha256: hex::encode(Sha256::digest(joined)),
+            },
+        );
+    }
+    println!(
+        "Erased {} objects of {publisher_user_id} across {} backends",
+        backends
+            .values()
+            .map(|erased| erased.deleted)
+            .sum::<usize>(),
+        backends.len()
+    );
+
+    Ok(SignedReceipt::of(Receipt {
+        publisher_user_id: publisher_user_id.to_string(),
+        video_ids,
+        backends,
+        erased_at: Utc::now(),
+    }))
+}
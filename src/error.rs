@@ -0,0 +1,320 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::reclassify::ReclassifyError;
+use crate::source::SourceError;
+use crate::store::{StoreError, UplinkFailure};
+
+/// Header carrying the id of a request, set on every response
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id accepted from a caller
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: String;
+}
+
+/// Stable, machine-readable reason of a failed request
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidInput,
+    Unauthorized,
+    Forbidden,
+    IdempotencyConflict,
+    JobNotFound,
+    VideoNotFound,
+    SourceNotFound,
+    SourceInvalid,
+    SourceUnauthorized,
+    SourceUnreachable,
+    SourceFailed,
+    StorjUploadFailed,
+    StorjAccessDenied,
+    StorjBucketNotFound,
+    StorjTimeout,
+    StorjRateLimited,
+    StorjFailed,
+    S3UploadFailed,
+    S3Timeout,
+    S3Failed,
+    StorageFailed,
+    IntegrityMismatch,
+    MoveFailed,
+    MoveIncomplete,
+    DeleteIncomplete,
+    Internal,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid input: {detail}")]
+    InvalidInput { status: StatusCode, detail: String },
+
+    #[error("missing or invalid API key")]
+    Unauthorized,
+
+    #[error("the API key lacks the scope for this route")]
+    Forbidden,
+
+    #[error("a request with this Idempotency-Key is still in progress")]
+    IdempotencyConflict,
+
+    #[error("no job with id {0}")]
+    JobNotFound(String),
+
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Hyper(#[from] axum::Error),
+
+    #[error(transparent)]
+    Source(#[from] SourceError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    Reclassify(#[from] ReclassifyError),
+
+    #[error("{failed} objects couldn't be deleted")]
+    DeleteIncomplete {
+        /// The first backend that failed
+        backend: Option<&'static str>,
+        failed: usize,
+        /// The deletion with what became of each object, merged into the body
+        report: serde_json::Value,
+    },
+}
+
+impl Error {
+    pub fn invalid_input(detail: impl Into<String>) -> Self {
+        Error::InvalidInput {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::InvalidInput { .. } => ErrorCode::InvalidInput,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::Forbidden => ErrorCode::Forbidden,
+            Error::IdempotencyConflict => ErrorCode::IdempotencyConflict,
+            Error::JobNotFound(_) => ErrorCode::JobNotFound,
+            Error::Network(_) | Error::Io(_) | Error::Hyper(_) => ErrorCode::Internal,
+            Error::Source(SourceError::NotFound { .. }) => ErrorCode::SourceNotFound,
+            Error::Source(SourceError::Invalid(_)) => ErrorCode::SourceInvalid,
+            Error::Source(SourceError::Unauthorized { .. }) => ErrorCode::SourceUnauthorized,
+            Error::Source(SourceError::Network { .. }) => ErrorCode::SourceUnreachable,
+            Error::Source(SourceError::Upstream { .. }) => ErrorCode::SourceFailed,
+            Error::Source(SourceError::Store(e)) | Error::Store(e) => store_code(e),
+            Error::Reclassify(ReclassifyError::NotFound(_)) => ErrorCode::VideoNotFound,
+            Error::Reclassify(ReclassifyError::Copy { .. }) => ErrorCode::MoveFailed,
+            Error::Reclassify(ReclassifyError::Cleanup { .. }) => ErrorCode::MoveIncomplete,
+            Error::Reclassify(ReclassifyError::Store(e)) => store_code(e),
+            Error::DeleteIncomplete { .. } => ErrorCode::DeleteIncomplete,
+        }
+    }
+
+    /// Whether sending the same request again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::InvalidInput { .. }
+            | Error::Unauthorized
+            | Error::Forbidden
+            | Error::JobNotFound(_) => false,
+            Error::IdempotencyConflict => true,
+            Error::Network(_) | Error::Io(_) | Error::Hyper(_) => true,
+            Error::Source(SourceError::Upstream { status, .. }) => status.is_server_error(),
+            Error::Source(SourceError::Network { .. }) => true,
+            Error::Source(SourceError::Store(e)) | Error::Store(e) => e.is_retryable(),
+            Error::Source(_) => false,
+            Error::Reclassify(ReclassifyError::NotFound(_)) => false,
+            // A failed copy was rolled back and a failed cleanup is finished by a retry
+            Error::Reclassify(ReclassifyError::Copy { .. } | ReclassifyError::Cleanup { .. }) => {
+                true
+            }
+            Error::Reclassify(ReclassifyError::Store(e)) => e.is_retryable(),
+            // Deleting again retries only what is left
+            Error::DeleteIncomplete { .. } => true,
+        }
+    }
+
+    /// Name of the storage backend that failed, if any
+    pub fn backend(&self) -> Option<&'static str> {
+        match self {
+            Error::Source(SourceError::Store(e))
+            | Error::Store(e)
+            | Error::Reclassify(
+                ReclassifyError::Store(e)
+                | ReclassifyError::Copy { source: e, .. }
+                | ReclassifyError::Cleanup { source: e, .. },
+            ) => e.backend(),
+            Error::DeleteIncomplete { backend, .. } => *backend,
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidInput { status, .. } => *status,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::IdempotencyConflict => StatusCode::CONFLICT,
+            _ => match self.code() {
+                ErrorCode::JobNotFound | ErrorCode::VideoNotFound | ErrorCode::SourceNotFound => {
+                    StatusCode::NOT_FOUND
+                }
+                ErrorCode::SourceInvalid => StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::SourceFailed => StatusCode::BAD_REQUEST,
+                ErrorCode::SourceUnauthorized
+                | ErrorCode::SourceUnreachable
+                | ErrorCode::IntegrityMismatch => StatusCode::BAD_GATEWAY,
+                ErrorCode::S3Timeout | ErrorCode::StorjTimeout => StatusCode::GATEWAY_TIMEOUT,
+                ErrorCode::StorjRateLimited => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self.code() {
+            ErrorCode::InvalidInput => "Invalid input",
+            ErrorCode::Unauthorized => "Missing or invalid API key",
+            ErrorCode::Forbidden => "The API key isn't allowed to use this route",
+            ErrorCode::IdempotencyConflict => {
+                "A request with this Idempotency-Key is still in progress"
+            }
+            ErrorCode::JobNotFound => "No job with this id",
+            ErrorCode::VideoNotFound => "The video doesn't exist in storage",
+            ErrorCode::SourceNotFound => match self {
+                Error::Source(SourceError::NotFound {
+                    provider: "cloudflare",
+                }) => "The video doesn't exist on cloudflare",
+                _ => "The video doesn't exist at the source",
+            },
+            ErrorCode::SourceInvalid => "The video source is invalid",
+            ErrorCode::SourceUnauthorized => "The video source rejected our credentials",
+            ErrorCode::SourceUnreachable => "The video source couldn't be reached",
+            ErrorCode::SourceFailed => "The video couldn't be fetched from the source",
+            ErrorCode::StorjUploadFailed => "Uploading to storj failed",
+            ErrorCode::StorjAccessDenied => "Storj rejected our access grant",
+            ErrorCode::StorjBucketNotFound => "The storj bucket doesn't exist",
+            ErrorCode::StorjTimeout => "Storj operation timed out",
+            ErrorCode::StorjRateLimited => "Storj is rate limiting us",
+            ErrorCode::StorjFailed => "Storj operation failed",
+            ErrorCode::S3UploadFailed => "Uploading to S3 failed",
+            ErrorCode::S3Timeout => "S3 operation timed out",
+            ErrorCode::S3Failed => "S3 operation failed",
+            ErrorCode::StorageFailed => "Storage operation failed",
+            ErrorCode::IntegrityMismatch => "The stored copy doesn't match the source",
+            ErrorCode::MoveFailed => "Failed to copy the video to the other tier, nothing was moved",
+            ErrorCode::MoveIncomplete => {
+                "The video was copied, but removing it from its old tier failed. Retry to finish the move."
+            }
+            ErrorCode::DeleteIncomplete => {
+                "Some objects couldn't be deleted. Retry to finish the deletion."
+            }
+            ErrorCode::Internal => "Internal server error",
+        }
+    }
+}
+
+fn store_code(err: &StoreError) -> ErrorCode {
+    match err {
+        StoreError::NotFound(_) => ErrorCode::VideoNotFound,
+        StoreError::Integrity(_) => ErrorCode::IntegrityMismatch,
+        StoreError::S3Timeout(_) => ErrorCode::S3Timeout,
+        StoreError::S3(_) => ErrorCode::S3Failed,
+        StoreError::Uplink(err) => match err.failure {
+            UplinkFailure::ObjectNotFound => ErrorCode::VideoNotFound,
+            UplinkFailure::InvalidAccess => ErrorCode::StorjAccessDenied,
+            UplinkFailure::BucketNotFound => ErrorCode::StorjBucketNotFound,
+            UplinkFailure::Timeout => ErrorCode::StorjTimeout,
+            UplinkFailure::RateLimited => ErrorCode::StorjRateLimited,
+            UplinkFailure::UnexpectedOutput | UplinkFailure::Other => ErrorCode::StorjFailed,
+        },
+        StoreError::Io(_) => ErrorCode::StorageFailed,
+        StoreError::Upload { source, .. } => match store_code(source) {
+            ErrorCode::S3Failed => ErrorCode::S3UploadFailed,
+            ErrorCode::StorjFailed => ErrorCode::StorjUploadFailed,
+            code => code,
+        },
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let request_id = current_request_id();
+        let code = self.code();
+        eprintln!(
+            "err [{}] {code:?}: {self}",
+            request_id.as_deref().unwrap_or("-")
+        );
+
+        let mut body = json!({
+            "message": self.message(),
+            "code": code,
+            "retryable": self.is_retryable(),
+            "backend": self.backend(),
+            "request_id": request_id,
+        });
+        match &self {
+            Error::InvalidInput { detail, .. } => body["detail"] = detail.as_str().into(),
+            Error::DeleteIncomplete {
+                report: serde_json::Value::Object(report),
+                ..
+            } => {
+                for (field, value) in report {
+                    body[field] = value.clone();
+                }
+            }
+            _ => {}
+        }
+
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// Id of the request being handled, if called while handling one
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Give every request an id, taken from `X-Request-Id` if the caller sent a
+/// usable one, and echo it in the response
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let mut response = CURRENT_REQUEST_ID
+        .scope(id.clone(), next.run(request))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID.clone(), value);
+    }
+    response
+}
@@ -0,0 +1,234 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::routes::videos::TIERS;
+use crate::store::{self, ByteStream, ObjectInfo, ObjectStore, Storage, StoreError};
+
+/// Tar archives are made of blocks of this many bytes
+const BLOCK_SIZE: u64 = 512;
+
+/// Chunks buffered between reading the objects and sending the archive
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Path of the manifest in the archive
+const MANIFEST_PATH: &str = "manifest.json";
+
+/// An object of a video as exported
+#[derive(Serialize, Debug)]
+pub struct ExportedObject {
+    /// Path of the object in the archive
+    pub path: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// Metadata written when the video was duplicated
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedVideo {
+    pub video_id: String,
+    pub tier: &'static str,
+    pub video: Option<ExportedObject>,
+    pub thumbnail: Option<ExportedObject>,
+}
+
+/// Contents of `manifest.json`, the first file of the archive
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub publisher_user_id: String,
+    pub exported_at: DateTime<Utc>,
+    pub videos: Vec<ExportedVideo>,
+}
+
+/// An object to be read into the archive
+struct Entry {
+    store: Arc<dyn ObjectStore>,
+    info: ObjectInfo,
+    size: u64,
+    path: String,
+}
+
+/// Everything a publisher has stored, ready to be streamed as a tar archive
+pub struct Export {
+    manifest: Vec<u8>,
+    entries: Vec<Entry>,
+    exported_at: DateTime<Utc>,
+}
+
+/// Size of a file in the archive, padded to whole blocks
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+fn header(path: &str, size: u64, mtime: DateTime<Utc>) -> std::io::Result<Bytes> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(path)?;
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime.timestamp().max(0) as u64);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    Ok(Bytes::copy_from_slice(header.as_bytes()))
+}
+
+fn padding(size: u64) -> Bytes {
+    Bytes::from(vec![0; (padded(size) - size) as usize])
+}
+
+/// Look up the mp4s and thumbnails a publisher has in both tiers, along with
+/// the store each of them is read from
+pub async fn prepare(storage: &Storage, publisher_user_id: &str) -> Result<Export, StoreError> {
+    let exported_at = Utc::now();
+    let mut videos = vec![];
+    let mut entries = vec![];
+
+    for (tier_name, is_nsfw) in TIERS {
+        let tier = storage.tier(is_nsfw);
+        let mut found: BTreeMap<String, ExportedVideo> = BTreeMap::new();
+        for key in tier.list(&format!("{publisher_user_id}/")).await? {
+            let Some(video_id) = store::video_id_of(publisher_user_id, &key) else {
+                continue;
+            };
+            // e.g. deleted since it was listed
+            let Some(source) = tier.locate(&key).await? else {
+                continue;
+            };
+            let Some(info) = source.head(&key).await? else {
+                continue;
+            };
+            let size = info
+                .size
+                .ok_or_else(|| StoreError::Integrity(format!("size of {key} is unknown")))?;
+
+            let is_video = key.ends_with(".mp4");
+            let file_name = if is_video {
+                "video.mp4"
+            } else {
+                "thumbnail.png"
+            };
+            let path = format!("{tier_name}/{video_id}/{file_name}");
+            let exported = ExportedObject {
+                path: path.clone(),
+                size,
+                content_type: info.content_type.clone(),
+                metadata: info.metadata.clone(),
+            };
+
+            let video = found
+                .entry(video_id.to_string())
+                .or_insert_with(|| ExportedVideo {
+                    video_id: video_id.to_string(),
+                    tier: tier_name,
+                    video: None,
+                    thumbnail: None,
+                });
+            if is_video {
+                video.video = Some(exported);
+            } else {
+                video.thumbnail = Some(exported);
+            }
+            entries.push(Entry {
+                store: source,
+                info,
+                size,
+                path,
+            });
+        }
+        videos.extend(found.into_values());
+    }
+
+    let manifest = Manifest {
+        publisher_user_id: publisher_user_id.to_string(),
+        exported_at,
+        videos,
+    };
+    Ok(Export {
+        manifest: serde_json::to_vec_pretty(&manifest).expect("manifests to be serializable"),
+        entries,
+        exported_at,
+    })
+}
+
+impl Export {
+    /// Number of objects in the archive, besides the manifest
+    pub fn objects(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Exact size of the archive in bytes
+    pub fn size(&self) -> u64 {
+        let files = std::iter::once(self.manifest.len() as u64)
+            .chain(self.entries.iter().map(|entry| entry.size))
+            .map(|size| BLOCK_SIZE + padded(size))
+            .sum::<u64>();
+        // The archive ends with two empty blocks
+        files + 2 * BLOCK_SIZE
+    }
+
+    /// Stream the archive: the manifest first, then every object as it is read
+    /// from its store, so at most [`CHANNEL_CAPACITY`] chunks are held in memory.
+    ///
+    /// If an object can't be read completely or doesn't match its checksum, the
+    /// stream ends with an error instead of producing a broken archive.
+    pub fn stream(self) -> ByteStream {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            if let Err(err) = self.write(&tx).await {
+                eprintln!("Export failed: {err}");
+                tx.send(Err(err)).await.ok();
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+
+    async fn write(self, tx: &mpsc::Sender<std::io::Result<Bytes>>) -> std::io::Result<()> {
+        // Nobody is listening anymore once the receiver is gone
+        let send = |chunk: Bytes| async move {
+            tx.send(Ok(chunk))
+                .await
+                .map_err(|_| std::io::Error::other("the export was cancelled"))
+        };
+
+        let size = self.manifest.len() as u64;
+        send(header(MANIFEST_PATH, size, self.exported_at)?).await?;
+        send(Bytes::from(self.manifest)).await?;
+        send(padding(size)).await?;
+
+        for entry in self.entries {
+            let key = &entry.info.key;
+            send(header(&entry.path, entry.size, self.exported_at)?).await?;
+
+            let body = entry.store.get(key).await.map_err(std::io::Error::other)?;
+            let (mut body, checksum) = store::checksummed(body, Some(entry.size));
+            let mut read = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                read += chunk.len() as u64;
+                if read > entry.size {
+                    return Err(std::io::Error::other(format!(
+                        "{key} is larger than the {} bytes it was looked up with",
+                        entry.size
+                    )));
+                }
+                send(chunk).await?;
+            }
+            let checksum = checksum
+                .await
+                .map_err(|_| std::io::Error::other(format!("{key} couldn't be read")))?;
+            checksum.verify(&entry.info).map_err(|e| {
+                std::io::Error::other(format!("{} copy of {e}", entry.store.name()))
+            })?;
+
+            send(padding(entry.size)).await?;
+        }
+
+        send(Bytes::from(vec![0; 2 * BLOCK_SIZE as usize])).await?;
+        Ok(())
+    }
+}
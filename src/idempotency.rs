@@ -0,0 +1,255 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::auth::Caller;
+use crate::error::Error;
+use crate::store::{self, Checksum};
+
+/// Header a caller sets to make retries of a request safe
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Header set on responses that are replayed from the cache
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+/// Longest idempotency key accepted
+const MAX_KEY_LEN: usize = 255;
+/// Largest response that is cached, our responses are small json documents
+const MAX_CACHED_BODY: usize = 1024 * 1024;
+
+enum Slot {
+    /// The first request with the key is still being handled
+    InFlight,
+    Done {
+        response: CachedResponse,
+        /// SHA-256 of the body of the first request, `None` if its handler
+        /// didn't read the body to the end
+        body_sha256: Option<String>,
+        expires_at: Instant,
+    },
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn replay(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// What a request finds when it claims its key
+enum Claimed {
+    New,
+    InFlight,
+    Done {
+        response: CachedResponse,
+        body_sha256: Option<String>,
+    },
+}
+
+/// Responses of requests carrying an `Idempotency-Key`, kept for `ttl`
+#[derive(Clone)]
+pub struct IdempotencyCache {
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+    ttl: Duration,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            slots: Default::default(),
+            ttl,
+        }
+    }
+
+    /// Claim `key` for a new request, or return what the first request with the key got
+    fn claim(&self, key: &str) -> Claimed {
+        let mut slots = self
+            .slots
+            .lock()
+            .expect("idempotency lock to not be poisoned");
+        let now = Instant::now();
+        slots
+            .retain(|_, slot| !matches!(slot, Slot::Done { expires_at, .. } if *expires_at <= now));
+
+        match slots.get(key) {
+            Some(Slot::InFlight) => Claimed::InFlight,
+            Some(Slot::Done {
+                response,
+                body_sha256,
+                ..
+            }) => Claimed::Done {
+                response: response.clone(),
+                body_sha256: body_sha256.clone(),
+            },
+            None => {
+                slots.insert(key.to_string(), Slot::InFlight);
+                Claimed::New
+            }
+        }
+    }
+
+    fn complete(&self, key: &str, response: CachedResponse, body_sha256: Option<String>) {
+        self.slots
+            .lock()
+            .expect("idempotency lock to not be poisoned")
+            .insert(
+                key.to_string(),
+                Slot::Done {
+                    response,
+                    body_sha256,
+                    expires_at: Instant::now() + self.ttl,
+                },
+            );
+    }
+
+    fn release(&self, key: &str) {
+        self.slots
+            .lock()
+            .expect("idempotency lock to not be poisoned")
+            .remove(key);
+    }
+}
+
+/// Frees a claimed key if the request is dropped before it completes,
+/// e.g. because the client disconnected
+struct Claim<'a> {
+    cache: &'a IdempotencyCache,
+    key: &'a str,
+    done: bool,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cache.release(self.key);
+        }
+    }
+}
+
+/// Hash the body of a request as its handler reads it
+fn fingerprinted(request: Request) -> (Request, oneshot::Receiver<Checksum>) {
+    let (parts, body) = request.into_parts();
+    let body = body
+        .into_data_stream()
+        .map_err(std::io::Error::other)
+        .boxed();
+    let (body, checksum) = store::checksummed(body, None);
+    (
+        Request::from_parts(parts, Body::from_stream(body)),
+        checksum,
+    )
+}
+
+/// Replay the original response to requests repeating an `Idempotency-Key`.
+///
+/// Keys are scoped to the caller and the method, path and query of the
+/// request. A repeated key gets `409 Conflict` while the first request is
+/// still running, and `422` if its body differs from the first one. Server
+/// errors are not cached, so a request that failed that way can be retried
+/// for real.
+pub async fn idempotency(
+    State(cache): State<IdempotencyCache>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let Some(key) = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+    else {
+        return Error::InvalidInput {
+            status: StatusCode::BAD_REQUEST,
+            detail: "Idempotency-Key must be 1 to 255 visible ascii characters".into(),
+        }
+        .into_response();
+    };
+    // Authorization runs first, so every request carrying a key has a caller
+    let caller = request
+        .extensions()
+        .get::<Caller>()
+        .map(|caller| caller.name.clone())
+        .unwrap_or_default();
+    let key = format!("{caller} {} {} {key}", request.method(), request.uri());
+
+    let (request, mut body_checksum) = fingerprinted(request);
+    match cache.claim(&key) {
+        Claimed::New => {}
+        Claimed::Done {
+            response,
+            body_sha256,
+        } => {
+            let Some(expected) = body_sha256 else {
+                return response.replay();
+            };
+            // Read the body only to hash it, a body that can't be read doesn't match
+            let mut body = request.into_body().into_data_stream();
+            while let Some(Ok(_)) = body.next().await {}
+            drop(body);
+            return match body_checksum.await {
+                Ok(checksum) if checksum.sha256 == expected => response.replay(),
+                _ => Error::invalid_input(
+                    "Idempotency-Key was already used for a request with a different body",
+                )
+                .into_response(),
+            };
+        }
+        Claimed::InFlight => return Error::IdempotencyConflict.into_response(),
+    }
+    let mut claim = Claim {
+        cache: &cache,
+        key: &key,
+        done: false,
+    };
+
+    let response = next.run(request).await;
+    if response.status().is_server_error() {
+        return response;
+    }
+    let body_sha256 = body_checksum
+        .try_recv()
+        .ok()
+        .map(|checksum| checksum.sha256);
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_CACHED_BODY).await {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("Couldn't buffer response for idempotency key {key}: {e}");
+            return Error::Hyper(e).into_response();
+        }
+    };
+
+    let response = CachedResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    };
+    cache.complete(&key, response, body_sha256);
+    claim.done = true;
+
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,688 @@
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use storj_interface::{duplicate, ids::PublisherId, move2nsfw, move2sfw, reconcile};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinSet;
+
+use crate::callback::{self, CallbackStatus, Report};
+use crate::consts::{JOB_MAX_ATTEMPTS, REPAIR_JOB_MAX_ATTEMPTS};
+use crate::erasure;
+use crate::error::{Error, ErrorCode};
+use crate::extract::Json;
+use crate::reclassify::Direction;
+use crate::repair;
+use crate::routes;
+use crate::store::{progress::BackendProgress, Consistency, Outcomes, Progress, Storage};
+
+pub mod store;
+
+pub use store::JobStore;
+
+/// Delay before the first retry of a failed job, doubled for every further attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Upper bound of the delay before retrying a failed job
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How long finished jobs are kept around for status queries
+const FINISHED_JOB_RETENTION: chrono::TimeDelta = chrono::TimeDelta::days(7);
+
+/// How often finished jobs past their retention are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Number of times a callback is attempted
+const CALLBACK_MAX_ATTEMPTS: u32 = 6;
+
+/// Delay before the first retry of a failed callback, doubled for every further attempt
+const CALLBACK_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How long shutdown waits for running jobs before interrupting them
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(25);
+
+/// Query parameters deciding whether a request runs inline or as a job
+#[derive(Deserialize, Default)]
+pub struct Dispatch {
+    /// Enqueue the work and return a job id instead of waiting for it
+    #[serde(default, rename = "async")]
+    pub run_async: bool,
+}
+
+/// The work a job performs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Duplicate(duplicate::Args),
+    Finalize {
+        params: routes::duplicate::RawFinalizeParams,
+        body: routes::duplicate::RawFinalizeBody,
+    },
+    Move(move2nsfw::Args),
+    MoveToSfw(move2sfw::Args),
+    /// Copy objects to the stores of their tier that missed writing them
+    Repair {
+        is_nsfw: bool,
+        keys: Vec<String>,
+        /// Key to the backends that took the write, to repair the others from
+        #[serde(default)]
+        sources: BTreeMap<String, Vec<String>>,
+    },
+    Reconcile(reconcile::Args),
+    /// Delete everything a publisher has stored
+    Erase {
+        publisher_user_id: PublisherId,
+    },
+}
+
+impl JobKind {
+    pub fn operation(&self) -> &'static str {
+        match self {
+            JobKind::Duplicate(_) => "duplicate",
+            JobKind::Finalize { .. } => "finalize",
+            JobKind::Move(_) => "move",
+            JobKind::MoveToSfw(_) => "move_to_sfw",
+            JobKind::Repair { .. } => "repair",
+            JobKind::Reconcile(_) => "reconcile",
+            JobKind::Erase { .. } => "erase",
+        }
+    }
+
+    /// Number of times the job is attempted before it is marked as failed
+    fn max_attempts(&self) -> u32 {
+        match self {
+            JobKind::Repair { .. } => *REPAIR_JOB_MAX_ATTEMPTS,
+            _ => *JOB_MAX_ATTEMPTS,
+        }
+    }
+
+    fn callback_url(&self) -> Option<&str> {
+        match self {
+            JobKind::Duplicate(args) => args.callback_url.as_deref(),
+            JobKind::Finalize { body, .. } => body.callback_url.as_deref(),
+            JobKind::Move(args) => args.callback_url.as_deref(),
+            JobKind::MoveToSfw(args) => args.callback_url.as_deref(),
+            JobKind::Repair { .. } | JobKind::Reconcile(_) | JobKind::Erase { .. } => None,
+        }
+    }
+
+    /// The tier (nsfw or not) and keys of the objects the job writes
+    pub async fn objects(&self, storage: &Storage) -> (bool, Vec<String>) {
+        let (publisher_user_id, video_id, is_nsfw) = match self {
+            JobKind::Duplicate(args) => (&args.publisher_user_id, &args.video_id, args.is_nsfw),
+            JobKind::Finalize { params, .. } => {
+                (&params.publisher_user_id, &params.video_id, params.is_nsfw)
+            }
+            JobKind::Move(args) => (&args.publisher_user_id, &args.video_id, true),
+            JobKind::MoveToSfw(args) => (&args.publisher_user_id, &args.video_id, false),
+            JobKind::Repair { is_nsfw, keys, .. } => return (*is_nsfw, keys.clone()),
+            // Only known once the job ran
+            JobKind::Reconcile(_) | JobKind::Erase { .. } => return (false, vec![]),
+        };
+
+        let mut keys = vec![
+            crate::store::video_key(publisher_user_id, video_id),
+            crate::store::thumbnail_key(publisher_user_id, video_id),
+        ];
+        // Moves take the HLS tree along
+        if let JobKind::Move(_) | JobKind::MoveToSfw(_) = self {
+            let hls = storage
+                .tier(is_nsfw)
+                .list(&crate::store::hls_key(video_id, ""))
+                .await
+                .inspect_err(|e| eprintln!("Couldn't list the HLS tree of {video_id}: {e}"))
+                .unwrap_or_default();
+            keys.extend(hls);
+        }
+
+        (is_nsfw, keys)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub state: JobState,
+    pub request: JobKind,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Number of times the job has been started
+    #[serde(default)]
+    pub attempts: u32,
+    /// A queued job that failed before is retried no earlier than this
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    /// Per-backend progress of the current attempt
+    pub progress: BTreeMap<String, BackendProgress>,
+    /// What the operation returned, once it succeeded
+    pub result: Option<Value>,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    /// Machine-readable reason of the last failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    /// Delivery of the callback, if one was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<CallbackStatus>,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Succeeded | JobState::Failed)
+    }
+
+    /// Whether the callback still has to be (re)sent
+    fn callback_pending(&self) -> bool {
+        self.is_finished()
+            && self
+                .callback
+                .as_ref()
+                .is_some_and(|status| !status.delivered && status.attempts < CALLBACK_MAX_ATTEMPTS)
+    }
+}
+
+struct Entry {
+    job: Job,
+    progress: Progress,
+}
+
+/// Queue of jobs worked off by a fixed number of workers.
+///
+/// Every state change is written to a [`JobStore`], so queued and interrupted
+/// jobs are picked up again after a restart.
+#[derive(Clone)]
+pub struct JobQueue {
+    jobs: Arc<RwLock<HashMap<String, Entry>>>,
+    store: JobStore,
+    storage: Storage,
+    tx: mpsc::UnboundedSender<String>,
+    shutdown: Arc<watch::Sender<bool>>,
+    workers: Arc<std::sync::Mutex<JoinSet<()>>>,
+}
+
+impl JobQueue {
+    /// Resume the jobs persisted in `store` and spawn `workers` workers executing them
+    pub async fn start(store: JobStore, storage: Storage, workers: usize) -> std::io::Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (shutdown, _) = watch::channel(false);
+        let queue = Self {
+            jobs: Default::default(),
+            store,
+            storage,
+            tx,
+            shutdown: Arc::new(shutdown),
+            workers: Default::default(),
+        };
+
+        queue.resume().await?;
+
+        let rx = Arc::new(Mutex::new(rx));
+        let mut set = queue
+            .workers
+            .lock()
+            .expect("workers lock to not be poisoned");
+        for _ in 0..workers.max(1) {
+            set.spawn(work(queue.clone(), rx.clone(), queue.shutdown.subscribe()));
+        }
+        drop(set);
+
+        tokio::spawn(prune(queue.clone(), queue.shutdown.subscribe()));
+
+        Ok(queue)
+    }
+
+    /// Load the persisted jobs, requeueing the unfinished ones
+    async fn resume(&self) -> std::io::Result<()> {
+        let now = Utc::now();
+        let mut resumed = 0;
+
+        for mut job in self.store.load().await? {
+            match job.state {
+                JobState::Succeeded | JobState::Failed => {
+                    if now - job.updated_at > FINISHED_JOB_RETENTION {
+                        self.store.remove(&job.id).await?;
+                        continue;
+                    }
+                }
+                // The process died in the middle of an attempt
+                JobState::Running => {
+                    if job.attempts >= job.request.max_attempts() {
+                        job.state = JobState::Failed;
+                        job.error = Some("interrupted by a restart on its last attempt".into());
+                    } else {
+                        job.state = JobState::Queued;
+                    }
+                    job.updated_at = now;
+                    self.store.save(&job).await?;
+                }
+                JobState::Queued => {}
+            }
+
+            let queued = (job.state == JobState::Queued).then(|| (job.id.clone(), job.not_before));
+            if job.callback_pending() {
+                self.notify(&job);
+            }
+            self.jobs
+                .write()
+                .expect("jobs lock to not be poisoned")
+                .insert(
+                    job.id.clone(),
+                    Entry {
+                        job,
+                        progress: Progress::default(),
+                    },
+                );
+            if let Some((id, not_before)) = queued {
+                self.schedule(id, not_before);
+                resumed += 1;
+            }
+        }
+
+        if resumed > 0 {
+            println!("Resumed {resumed} unfinished jobs");
+        }
+
+        Ok(())
+    }
+
+    /// Forget the finished jobs past [`FINISHED_JOB_RETENTION`], in memory and on disk
+    async fn prune_finished(&self) {
+        let cutoff = Utc::now() - FINISHED_JOB_RETENTION;
+        let expired: Vec<String> = {
+            let mut jobs = self.jobs.write().expect("jobs lock to not be poisoned");
+            let expired: Vec<String> = jobs
+                .values()
+                .filter(|entry| entry.job.is_finished() && entry.job.updated_at < cutoff)
+                .map(|entry| entry.job.id.clone())
+                .collect();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            expired
+        };
+
+        for id in &expired {
+            if let Err(e) = self.store.remove(id).await {
+                eprintln!("Failed to remove finished job {id}: {e}");
+            }
+        }
+        if !expired.is_empty() {
+            println!("Pruned {} finished jobs", expired.len());
+        }
+    }
+
+    /// Hand a job to the workers once `not_before` has passed
+    fn schedule(&self, id: String, not_before: Option<DateTime<Utc>>) {
+        let delay = not_before.and_then(|at| (at - Utc::now()).to_std().ok());
+        let tx = self.tx.clone();
+        match delay {
+            Some(delay) => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    // fails only once the workers are gone, the job stays persisted as queued
+                    tx.send(id).ok();
+                });
+            }
+            None => {
+                tx.send(id).ok();
+            }
+        }
+    }
+
+    /// Persist a new job and queue it
+    pub async fn enqueue(&self, request: JobKind) -> std::io::Result<Accepted> {
+        let now = Utc::now();
+        let id = uuid::Uuid::new_v4().to_string();
+        let job = Job {
+            id: id.clone(),
+            state: JobState::Queued,
+            request: request.clone(),
+            created_at: now,
+            updated_at: now,
+            attempts: 0,
+            not_before: None,
+            progress: Default::default(),
+            result: None,
+            error: None,
+            error_code: None,
+            callback: request.callback_url().map(|_| CallbackStatus::default()),
+        };
+
+        self.store.save(&job).await?;
+        self.jobs
+            .write()
+            .expect("jobs lock to not be poisoned")
+            .insert(
+                id.clone(),
+                Entry {
+                    job,
+                    progress: Progress::default(),
+                },
+            );
+        self.schedule(id.clone(), None);
+
+        Ok(Accepted { id })
+    }
+
+    /// Report how consistent the backends of a tier are after a write by a
+    /// tolerant tier, queueing a repair of the objects some of them missed
+    pub async fn settle(&self, is_nsfw: bool, outcomes: &Outcomes) -> std::io::Result<Consistency> {
+        let keys = outcomes.divergent_keys();
+        if keys.is_empty() {
+            return Ok(outcomes.consistency(None));
+        }
+
+        let sources = outcomes.sources();
+        let Accepted { id } = self
+            .enqueue(JobKind::Repair {
+                is_nsfw,
+                keys,
+                sources,
+            })
+            .await?;
+        eprintln!("Some backends missed writes, queued repair job {id}");
+        Ok(outcomes.consistency(Some(id)))
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let jobs = self.jobs.read().expect("jobs lock to not be poisoned");
+        let entry = jobs.get(id)?;
+        let mut job = entry.job.clone();
+        job.progress = entry.progress.snapshot();
+        Some(job)
+    }
+
+    /// Change a job and persist the result
+    async fn update(&self, id: &str, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let job = {
+            let mut jobs = self.jobs.write().expect("jobs lock to not be poisoned");
+            let entry = jobs.get_mut(id)?;
+            f(&mut entry.job);
+            entry.job.updated_at = Utc::now();
+            entry.job.progress = entry.progress.snapshot();
+            entry.job.clone()
+        };
+
+        if let Err(e) = self.store.save(&job).await {
+            eprintln!("Failed to persist job {id}: {e}");
+        }
+
+        Some(job)
+    }
+
+    /// Send the callback of a finished job in the background, retrying with backoff
+    fn notify(&self, job: &Job) {
+        let Some(url) = job.request.callback_url().map(str::to_string) else {
+            return;
+        };
+        let queue = self.clone();
+        let job = job.clone();
+
+        tokio::spawn(async move {
+            let report = Report::of(&job, &queue.storage).await;
+            let mut attempts = job.callback.map(|status| status.attempts).unwrap_or(0);
+
+            while attempts < CALLBACK_MAX_ATTEMPTS {
+                if attempts > 0 {
+                    tokio::time::sleep(CALLBACK_BACKOFF * 2u32.pow(attempts - 1)).await;
+                }
+                attempts += 1;
+
+                let sent = callback::send(&url, &report).await;
+                if let Err(e) = &sent {
+                    eprintln!(
+                        "Callback for job {} failed (attempt {attempts}/{CALLBACK_MAX_ATTEMPTS}): {e}",
+                        report.job_id
+                    );
+                }
+                let delivered = sent.is_ok();
+                queue
+                    .update(&report.job_id, |job| {
+                        job.callback = Some(CallbackStatus {
+                            attempts,
+                            delivered,
+                            error: sent.err(),
+                        });
+                    })
+                    .await;
+
+                if delivered {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Stop taking up new jobs and wait for the running ones.
+    ///
+    /// Jobs still running after [`SHUTDOWN_GRACE`] are interrupted and persisted
+    /// as queued, without counting the interrupted attempt.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let mut workers = std::mem::take(
+            &mut *self
+                .workers
+                .lock()
+                .expect("workers lock to not be poisoned"),
+        );
+
+        let finished = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            while workers.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_ok() {
+            return;
+        }
+
+        workers.shutdown().await;
+
+        let running: Vec<String> = self
+            .jobs
+            .read()
+            .expect("jobs lock to not be poisoned")
+            .values()
+            .filter(|entry| entry.job.state == JobState::Running)
+            .map(|entry| entry.job.id.clone())
+            .collect();
+        eprintln!(
+            "Interrupted {} running jobs, they will be resumed on the next start",
+            running.len()
+        );
+        for id in running {
+            self.update(&id, |job| {
+                job.state = JobState::Queued;
+                job.attempts = job.attempts.saturating_sub(1);
+            })
+            .await;
+        }
+    }
+}
+
+/// Prune finished jobs every [`PRUNE_INTERVAL`] until shutdown. Jobs that
+/// expired while the service was down are dropped when resuming them.
+async fn prune(queue: JobQueue, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes right away, resuming just pruned
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            biased;
+            _ = shutdown.wait_for(|stop| *stop) => return,
+            _ = interval.tick() => {}
+        }
+        queue.prune_finished().await;
+    }
+}
+
+async fn work(
+    queue: JobQueue,
+    rx: Arc<Mutex<mpsc::UnboundedReceiver<String>>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let next = {
+            let mut rx = rx.lock().await;
+            tokio::select! {
+                biased;
+                _ = shutdown.wait_for(|stop| *stop) => return,
+                id = rx.recv() => id,
+            }
+        };
+        let Some(id) = next else {
+            return;
+        };
+
+        // Every attempt reports to fresh progress
+        let progress = Progress::default();
+        let request = {
+            let mut jobs = queue.jobs.write().expect("jobs lock to not be poisoned");
+            match jobs.get_mut(&id) {
+                Some(entry) if entry.job.state == JobState::Queued => {
+                    entry.progress = progress.clone();
+                    entry.job.request.clone()
+                }
+                _ => continue,
+            }
+        };
+
+        let Some(job) = queue
+            .update(&id, |job| {
+                job.state = JobState::Running;
+                job.attempts += 1;
+                job.not_before = None;
+            })
+            .await
+        else {
+            continue;
+        };
+        let max_attempts = job.request.max_attempts();
+        println!("Running job {id} (attempt {}/{max_attempts})", job.attempts);
+
+        let result = execute(&queue, request, &progress).await;
+
+        let Some(job) = queue
+            .update(&id, |job| match result {
+                Ok(result) => {
+                    job.state = JobState::Succeeded;
+                    job.result = Some(result);
+                    job.error = None;
+                    job.error_code = None;
+                }
+                Err(err) if err.is_retryable() && job.attempts < max_attempts => {
+                    let backoff = RETRY_BACKOFF
+                        .saturating_mul(2u32.saturating_pow(job.attempts - 1))
+                        .min(MAX_RETRY_BACKOFF);
+                    eprintln!(
+                        "Job {id} failed on attempt {}, retrying in {}s: {err}",
+                        job.attempts,
+                        backoff.as_secs()
+                    );
+                    job.state = JobState::Queued;
+                    job.not_before = chrono::TimeDelta::from_std(backoff)
+                        .ok()
+                        .map(|backoff| Utc::now() + backoff);
+                    job.error = Some(err.to_string());
+                    job.error_code = Some(err.code());
+                }
+                Err(err) => {
+                    eprintln!("Job {id} failed: {err}");
+                    job.state = JobState::Failed;
+                    job.error = Some(err.to_string());
+                    job.error_code = Some(err.code());
+                }
+            })
+            .await
+        else {
+            continue;
+        };
+
+        if job.state == JobState::Queued {
+            queue.schedule(id, job.not_before);
+        } else if job.callback_pending() {
+            queue.notify(&job);
+        }
+    }
+}
+
+/// Run the operation behind a job, the same way the synchronous routes do
+async fn execute(queue: &JobQueue, request: JobKind, progress: &Progress) -> Result<Value, Error> {
+    fn finish<T: Serialize>(result: Result<T, Error>) -> Result<Value, Error> {
+        result.map(|value| serde_json::to_value(value).expect("results to be serializable"))
+    }
+
+    let storage = &queue.storage;
+    let outcomes = Outcomes::default();
+    match request {
+        JobKind::Duplicate(args) => {
+            let is_nsfw = args.is_nsfw;
+            let checksum = routes::duplicate::run(storage, args, progress, &outcomes).await?;
+            finish(Ok(routes::duplicate::Duplicated {
+                checksum,
+                consistency: queue.settle(is_nsfw, &outcomes).await?,
+            }))
+        }
+        JobKind::Finalize { params, body } => {
+            let is_nsfw = params.is_nsfw;
+            let checksum =
+                routes::duplicate::run_finalize(storage, params, body, progress, &outcomes).await?;
+            finish(Ok(routes::duplicate::Duplicated {
+                checksum,
+                consistency: queue.settle(is_nsfw, &outcomes).await?,
+            }))
+        }
+        JobKind::Move(args) => {
+            finish(routes::reclassify::run(storage, Direction::ToNsfw, args, progress).await)
+        }
+        JobKind::MoveToSfw(args) => {
+            finish(routes::reclassify::run(storage, Direction::ToSfw, args, progress).await)
+        }
+        JobKind::Repair {
+            is_nsfw,
+            keys,
+            sources,
+        } => finish(
+            repair::repair(storage, is_nsfw, &keys, &sources, progress)
+                .await
+                .map_err(Error::from),
+        ),
+        JobKind::Reconcile(args) => finish(routes::reconcile::run(storage, args, progress).await),
+        JobKind::Erase { publisher_user_id } => finish(
+            erasure::erase(storage, &publisher_user_id, progress)
+                .await
+                .map_err(Error::from),
+        ),
+    }
+}
+
+/// Response to a request that was turned into a job
+pub struct Accepted {
+    pub id: String,
+}
+
+impl IntoResponse for Accepted {
+    fn into_response(self) -> axum::response::Response {
+        (
+            StatusCode::ACCEPTED,
+            Json(json!({
+                "job_id": self.id,
+                "status": JobState::Queued,
+                "status_url": format!("/jobs/{}", self.id),
+            })),
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::path::PathBuf;
+
+use super::Job;
+
+/// Jobs persisted as one json file per job.
+///
+/// Files are replaced atomically (write to a temporary file, then rename),
+/// so a crash mid-write leaves the previous state of the job intact.
+#[derive(Clone)]
+pub struct JobStore {
+    dir: PathBuf,
+}
+
+impl JobStore {
+    pub async fn open(dir: PathBuf) -> io::Result<Self> {
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    pub async fn save(&self, job: &Job) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(job)?;
+        let tmp = self
+            .dir
+            .join(format!(".{}.{}.tmp", job.id, uuid::Uuid::new_v4()));
+
+        tokio::fs::write(&tmp, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, self.path(&job.id)).await {
+            tokio::fs::remove_file(&tmp).await.ok();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, id: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(id)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every job in the store. Unreadable files are logged and skipped.
+    pub async fn load(&self) -> io::Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => {}
+                // left behind by a write that was interrupted
+                Some("tmp") => {
+                    tokio::fs::remove_file(&path).await.ok();
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let job = tokio::fs::read(&path)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|data| serde_json::from_slice::<Job>(&data).map_err(|e| e.to_string()));
+            match job {
+                Ok(job) => jobs.push(job),
+                Err(e) => eprintln!("Skipping unreadable job file {}: {e}", path.display()),
+            }
+        }
+
+        Ok(jobs)
+    }
+}
@@ -1,41 +1,360 @@
+pub mod ids {
+    //! Validated identifiers that end up in object keys.
+    //!
+    //! They only consist of characters that are safe in `sj://` paths, S3 keys
+    //! and file names, so no value can escape the prefix it is meant for.
+
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::ops::Deref;
+    use std::str::FromStr;
+
+    /// Longest identifier accepted
+    pub const MAX_LEN: usize = 128;
+
+    #[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+    #[error("invalid {kind} {value:?}: {reason}")]
+    pub struct InvalidId {
+        pub kind: &'static str,
+        pub value: String,
+        pub reason: &'static str,
+    }
+
+    fn validate(kind: &'static str, value: &str, extra: &[char]) -> Result<(), InvalidId> {
+        let invalid = |reason| {
+            Err(InvalidId {
+                kind,
+                value: value.to_string(),
+                reason,
+            })
+        };
+
+        if value.is_empty() {
+            return invalid("must not be empty");
+        }
+        if value.len() > MAX_LEN {
+            return invalid("is too long");
+        }
+        if value.starts_with('.') {
+            return invalid("must not start with a dot");
+        }
+        if !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || extra.contains(&c))
+        {
+            return invalid("contains characters other than ascii letters, digits, '-' and '_'");
+        }
+
+        Ok(())
+    }
+
+    macro_rules! id {
+        ($(#[$doc:meta])* $name:ident, $kind:literal, $extra:expr) => {
+            $(#[$doc])*
+            #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+            #[serde(try_from = "String", into = "String")]
+            pub struct $name(String);
+
+            impl TryFrom<String> for $name {
+                type Error = InvalidId;
+
+                fn try_from(value: String) -> Result<Self, Self::Error> {
+                    validate($kind, &value, $extra)?;
+                    Ok(Self(value))
+                }
+            }
+
+            impl FromStr for $name {
+                type Err = InvalidId;
+
+                fn from_str(value: &str) -> Result<Self, Self::Err> {
+                    value.to_string().try_into()
+                }
+            }
+
+            impl From<$name> for String {
+                fn from(id: $name) -> Self {
+                    id.0
+                }
+            }
+
+            impl Deref for $name {
+                type Target = str;
+
+                fn deref(&self) -> &str {
+                    &self.0
+                }
+            }
+
+            impl AsRef<str> for $name {
+                fn as_ref(&self) -> &str {
+                    &self.0
+                }
+            }
+
+            impl fmt::Display for $name {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    f.write_str(&self.0)
+                }
+            }
+        };
+    }
+
+    id!(
+        /// The publisher user principal, used as directory key
+        PublisherId,
+        "publisher_user_id",
+        &[]
+    );
+    id!(
+        /// A video id, used as object key
+        VideoId,
+        "video_id",
+        &[]
+    );
+    id!(
+        /// Name of a playlist or segment in a video's HLS tree, e.g. `playlist_0.m3u8`
+        HlsFileName,
+        "hls_file_name",
+        &['.']
+    );
+}
+
+pub mod hosts {
+    //! Telling internal hosts, which requests must never be sent to on a
+    //! caller's behalf, from public ones.
+
+    use std::net::IpAddr;
+
+    /// Whether the address belongs to this machine or a private network
+    pub fn is_internal_ip(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => {
+                ip.is_loopback()
+                    || ip.is_private()
+                    || ip.is_link_local()
+                    || ip.is_unspecified()
+                    || ip.is_broadcast()
+                    // 100.64.0.0/10, shared address space
+                    || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+            }
+            IpAddr::V6(ip) => {
+                ip.to_ipv4_mapped().is_some_and(|ip| is_internal_ip(IpAddr::V4(ip)))
+                    || ip.is_loopback()
+                    || ip.is_unspecified()
+                    // fc00::/7 unique local and fe80::/10 link local addresses
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        }
+    }
+
+    /// Whether the host of a url is an internal address or a name reserved for
+    /// internal use. Names are not resolved.
+    pub fn is_internal_host(host: &str) -> bool {
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => is_internal_ip(ip),
+            Err(_) => {
+                let domain = host.trim_end_matches('.').to_ascii_lowercase();
+                domain == "localhost"
+                    || [".localhost", ".local", ".internal"]
+                        .iter()
+                        .any(|suffix| domain.ends_with(suffix))
+            }
+        }
+    }
+}
+
+pub mod callback {
+    //! Validated urls the outcome of a job is POSTed to.
+
+    use once_cell::sync::Lazy;
+    use serde::{Deserialize, Serialize};
+    use std::fmt;
+    use std::ops::Deref;
+
+    use crate::hosts::is_internal_host;
+
+    /// Accept plain `http://` callback urls and internal hosts, for testing only
+    static ALLOW_HTTP_CALLBACKS: Lazy<bool> = Lazy::new(|| {
+        std::env::var("ALLOW_HTTP_CALLBACKS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false)
+    });
+
+    #[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+    #[error("invalid callback_url {value:?}: {reason}")]
+    pub struct InvalidCallbackUrl {
+        pub value: String,
+        pub reason: String,
+    }
+
+    fn validate(value: &str) -> Result<(), String> {
+        let url = reqwest::Url::parse(value).map_err(|e| e.to_string())?;
+        match url.scheme() {
+            "https" => {}
+            "http" if *ALLOW_HTTP_CALLBACKS => {}
+            scheme => return Err(format!("{scheme} urls are not allowed, use https")),
+        }
+        if *ALLOW_HTTP_CALLBACKS {
+            return Ok(());
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err("has no host".into());
+        };
+        if is_internal_host(host) {
+            return Err("internal hosts are not allowed".into());
+        }
+        Ok(())
+    }
+
+    /// An `https` url on a public host. Host names are not resolved, so this
+    /// doesn't stop names pointing at internal addresses.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct CallbackUrl(String);
+
+    impl TryFrom<String> for CallbackUrl {
+        type Error = InvalidCallbackUrl;
+
+        fn try_from(value: String) -> Result<Self, Self::Error> {
+            match validate(&value) {
+                Ok(()) => Ok(Self(value)),
+                Err(reason) => Err(InvalidCallbackUrl { value, reason }),
+            }
+        }
+    }
+
+    impl From<CallbackUrl> for String {
+        fn from(url: CallbackUrl) -> Self {
+            url.0
+        }
+    }
+
+    impl Deref for CallbackUrl {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl fmt::Display for CallbackUrl {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+}
+
 pub mod duplicate {
     use std::collections::BTreeMap;
 
     use serde::{Deserialize, Serialize};
 
+    use crate::callback::CallbackUrl;
+    use crate::ids::{PublisherId, VideoId};
+
     /// Args for duplication request
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Args {
         /// The publisher user principal supplied to off chain agent
         ///
         /// This used as directory key
-        pub publisher_user_id: String,
+        pub publisher_user_id: PublisherId,
         /// The video id on cloudflare
         ///
         /// This is used as object key
-        pub video_id: String,
+        pub video_id: VideoId,
         /// Whether the video contains nsfw content
         ///
         /// This is used for segregation
         pub is_nsfw: bool,
         /// key-value pair to be added to video's metadata on storj
         pub metadata: BTreeMap<String, String>,
+        /// Where to fetch the video from
+        ///
+        /// Defaults to the video's download on cloudflare stream
+        #[serde(default)]
+        pub source: Source,
+        /// Url to POST the outcome to once the video is duplicated
+        ///
+        /// Requests with a callback url are run as background jobs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub callback_url: Option<CallbackUrl>,
+    }
+
+    /// Where the video to be duplicated is fetched from
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum Source {
+        /// The default mp4 download of `video_id` on cloudflare stream
+        #[default]
+        CloudflareStream,
+        /// Any https url serving the mp4
+        Url {
+            url: String,
+            /// Value of the authorization header sent along, if the url needs one.
+            ///
+            /// Never serialized, so it stays out of persisted jobs and their
+            /// status. Jobs resumed after a restart fetch the url without it.
+            #[serde(default, skip_serializing)]
+            authorization: Option<String>,
+        },
+        /// A video already stored by the storj interface
+        Object {
+            publisher_user_id: PublisherId,
+            video_id: VideoId,
+            is_nsfw: bool,
+        },
     }
 }
 
 pub mod move2nsfw {
     use serde::{Deserialize, Serialize};
 
+    use crate::callback::CallbackUrl;
+    use crate::ids::{PublisherId, VideoId};
+
     /// Args for moving a video to nsfw bucket
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Args {
         /// The publisher user principal supplied to off chain agent
         ///
         /// This used as directory key
-        pub publisher_user_id: String,
+        pub publisher_user_id: PublisherId,
         /// The video id on cloudflare
         ///
         /// This is used as object key
-        pub video_id: String,
+        pub video_id: VideoId,
+        /// Url to POST the outcome to once the video is moved
+        ///
+        /// Requests with a callback url are run as background jobs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub callback_url: Option<CallbackUrl>,
+    }
+}
+
+pub mod move2sfw {
+    /// Args for moving a video back to the sfw bucket, the same as for moving it to the nsfw bucket
+    pub use crate::move2nsfw::Args;
+}
+
+pub mod reconcile {
+    use serde::{Deserialize, Serialize};
+
+    use crate::ids::PublisherId;
+
+    /// Args for checking the sfw backends against each other
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Args {
+        /// The publisher user principal supplied to off chain agent
+        ///
+        /// Every video under this directory key is checked, along with its HLS tree
+        pub publisher_user_id: PublisherId,
+        /// Whether to copy objects that are missing or differ from the backend
+        /// holding a complete copy
+        #[serde(default)]
+        pub repair: bool,
     }
 }
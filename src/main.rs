@@ -1,26 +1,38 @@
 use anyhow::Context;
 use axum::{
-    extract::{DefaultBodyLimit, Request},
-    http::{HeaderMap, Method},
-    middleware::{self, Next},
-    response::IntoResponse,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    http::Method,
+    middleware,
+    routing::{delete, get, patch, post},
     Router,
 };
 use consts::{
-    ACCESS_GRANT_NSFW, ACCESS_GRANT_SFW, HETZNER_S3_ACCESS_KEY, HETZNER_S3_BUCKET,
-    HETZNER_S3_ENDPOINT, HETZNER_S3_REGION, HETZNER_S3_SECRET_KEY, SERVICE_SECRET_TOKEN,
-    YRAL_VIDEOS,
+    ACCESS_GRANT_NSFW, ACCESS_GRANT_SFW, API_KEYS, CALLBACK_SECRET, HETZNER_S3_ACCESS_KEY,
+    HETZNER_S3_BUCKET, HETZNER_S3_ENDPOINT, HETZNER_S3_REGION, HETZNER_S3_SECRET_KEY,
+    IDEMPOTENCY_TTL, JOB_STORE_DIR, JOB_WORKERS, SERVICE_SECRET_TOKEN, YRAL_VIDEOS,
 };
 use once_cell::sync::Lazy;
-use reqwest::{header::AUTHORIZATION, StatusCode};
 use std::sync::Arc;
 use tokio::{signal, sync::Notify};
 use tower_http::cors::{Any, CorsLayer};
 
+mod auth;
+mod callback;
 pub(crate) mod consts;
+mod erasure;
+mod error;
+mod export;
+mod extract;
+mod idempotency;
+mod jobs;
+mod reclassify;
+mod reconcile;
+mod repair;
 mod routes;
 mod s3_client;
+mod source;
+mod state;
+mod store;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -29,6 +41,8 @@ async fn main() -> anyhow::Result<()> {
     Lazy::force(&ACCESS_GRANT_NSFW);
     Lazy::force(&YRAL_VIDEOS);
     Lazy::force(&SERVICE_SECRET_TOKEN);
+    Lazy::force(&API_KEYS);
+    Lazy::force(&CALLBACK_SECRET);
 
     // Force loading of Hetzner S3 configuration
     Lazy::force(&HETZNER_S3_ENDPOINT);
@@ -39,45 +53,109 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize S3 client
     let s3_client = s3_client::S3Client::new().await;
+    let storage = store::Storage::new(s3_client);
+    tokio::spawn({
+        let storage = storage.clone();
+        async move { storage.clear_staging().await }
+    });
+
+    // Resume jobs that were queued or running when the service last stopped
+    let job_store = jobs::JobStore::open(JOB_STORE_DIR.clone())
+        .await
+        .with_context(|| format!("Couldn't open job store at {}", JOB_STORE_DIR.display()))?;
+    let jobs = jobs::JobQueue::start(job_store, storage.clone(), *JOB_WORKERS)
+        .await
+        .context("Couldn't resume jobs")?;
+    let state = state::AppState {
+        storage,
+        jobs: jobs.clone(),
+    };
+
+    let api_keys = auth::Registry::load()?;
+
+    // Replays responses to retried POST requests
+    let idempotent = middleware::from_fn_with_state(
+        idempotency::IdempotencyCache::new(*IDEMPOTENCY_TTL),
+        idempotency::idempotency,
+    );
 
     // Configure CORS to allow cross-origin requests
     let cors = CorsLayer::new()
         .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(Any);
-
-    let app = Router::new()
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::DELETE,
+            Method::PATCH,
+            Method::OPTIONS,
+        ])
+        .allow_headers(Any)
+        .expose_headers([error::REQUEST_ID.clone()]);
+
+    // Every route registered here requires authorization. Only add routes to `app`
+    // directly if they must be reachable without credentials.
+    let protected = Router::new()
         .route(
             "/duplicate",
-            post(routes::duplicate::handler)
-                .with_state(s3_client.clone())
-                .layer(middleware::from_fn(authorize)),
+            post(routes::duplicate::handler).layer(idempotent.clone()),
         )
         .route(
             "/duplicate_raw/upload",
             post(routes::duplicate::handler_raw_upload_initial)
-                .with_state(s3_client.clone())
-                .layer(DefaultBodyLimit::max(500 * 1024 * 1024)), // 500MB limit for raw video upload
+                .layer(DefaultBodyLimit::max(500 * 1024 * 1024)) // 500MB limit for raw video upload
+                .layer(idempotent.clone()),
         )
         .route(
             "/duplicate_raw/finalize",
-            post(routes::duplicate::handler_raw_finalize).with_state(s3_client.clone()),
+            post(routes::duplicate::handler_raw_finalize).layer(idempotent.clone()),
         )
         // NOTE: This will be removed as the upload happens in the very end of the pipeline and nsfw flag is passed into duplicate
         .route(
             "/move-to-nsfw",
-            post(routes::move2nsfw::handler)
-                .with_state(s3_client.clone())
-                .layer(middleware::from_fn(authorize)),
+            post(routes::move2nsfw::handler).layer(idempotent.clone()),
+        )
+        .route(
+            "/move-to-sfw",
+            post(routes::move2sfw::handler).layer(idempotent.clone()),
         )
         .route(
             "/hls/duplicate",
             post(routes::duplicate_hls::handler)
-                .with_state(s3_client.clone())
                 .layer(DefaultBodyLimit::max(100 * 1024 * 1024)) // 100MB limit for HLS files
-                .layer(middleware::from_fn(authorize)),
+                .layer(idempotent.clone()),
         )
+        .route(
+            "/reconcile",
+            post(routes::reconcile::handler).layer(idempotent.clone()),
+        )
+        .route("/jobs/{id}", get(routes::jobs::handler))
+        .route(
+            "/videos/{publisher_user_id}/{video_id}",
+            get(routes::videos::handler).delete(routes::videos::handler_delete),
+        )
+        .route(
+            "/videos/{publisher_user_id}/{video_id}/metadata",
+            patch(routes::videos::handler_update_metadata),
+        )
+        .route(
+            "/publishers/{publisher_user_id}",
+            delete(routes::publishers::handler_erase).layer(idempotent.clone()),
+        )
+        .route(
+            "/publishers/{publisher_user_id}/videos",
+            get(routes::publishers::handler_list),
+        )
+        .route(
+            "/publishers/{publisher_user_id}/export",
+            get(routes::publishers::handler_export),
+        )
+        .route_layer(middleware::from_fn_with_state(api_keys, auth::authorize))
+        .with_state(state);
+
+    let app = Router::new()
+        .merge(protected)
         .route("/health", get(health))
+        .layer(middleware::from_fn(error::request_id))
         .layer(cors);
 
     let addr = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
@@ -88,40 +166,57 @@ async fn main() -> anyhow::Result<()> {
     let notify_clone = notify.clone();
 
     tokio::spawn(async move {
-        if let Err(err) = signal::ctrl_c().await {
-            eprintln!("Failed to listen for shutdown signal: {err:#}");
-        }
+        shutdown_signal().await;
         notify_clone.notify_one();
     });
 
     println!("Starting to listen on http://localhost:3000");
 
-    server
+    let served = server
         .with_graceful_shutdown(async move {
             notify.notified().await;
             println!("Shutting down gracefully...");
         })
         .await
-        .context("Server error")
-}
+        .context("Server error");
 
-/// Simple path to check that the server is running
-async fn health() -> &'static str {
-    "alive"
+    // Let running jobs finish, anything left over is resumed on the next start
+    jobs.shutdown().await;
+
+    served
 }
 
-/// A dead simple authorization check based on a shared secret
-async fn authorize(
-    headers: HeaderMap,
-    request: Request,
-    next: Next,
-) -> Result<impl IntoResponse, StatusCode> {
-    let auth = headers.get(AUTHORIZATION).ok_or(StatusCode::UNAUTHORIZED)?;
-    let auth = auth.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    if auth != SERVICE_SECRET_TOKEN.as_str() {
-        return Err(StatusCode::UNAUTHORIZED);
+/// Resolves on ctrl-c, or on SIGTERM as sent by systemd
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            eprintln!("Failed to listen for shutdown signal: {err:#}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                eprintln!("Failed to listen for SIGTERM: {err:#}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
+}
 
-    Ok(next.run(request).await)
+/// Simple path to check that the server is running
+async fn health() -> &'static str {
+    "alive"
 }
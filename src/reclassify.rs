@@ -0,0 +1,197 @@
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::store::{
+    self, Checksum, ObjectInfo, ObjectStore, Progress, PutOptions, Storage, StoreError, Tier,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReclassifyError {
+    #[error("{0} exists in neither tier")]
+    NotFound(String),
+
+    #[error("copying {key} failed, the move was rolled back: {source}")]
+    Copy { key: String, source: StoreError },
+
+    #[error("{key} was copied, but removing it from the source tier failed: {source}")]
+    Cleanup { key: String, source: StoreError },
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// Tier a video is moved to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ToNsfw,
+    ToSfw,
+}
+
+impl Direction {
+    /// Whether the destination is the NSFW tier
+    pub fn to_nsfw(self) -> bool {
+        self == Direction::ToNsfw
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Direction::ToNsfw => "from SFW to NSFW tier",
+            Direction::ToSfw => "from NSFW to SFW tier",
+        })
+    }
+}
+
+/// Outcome of moving a video between tiers
+#[derive(Serialize, Debug)]
+pub struct Moved {
+    /// Keys of every object belonging to the video that was moved
+    pub objects: Vec<String>,
+    /// The video was in the destination tier already, only leftovers were removed from the source
+    pub already_moved: bool,
+}
+
+/// Move every object belonging to a video (mp4, thumbnail and HLS tree) to the
+/// tier it now belongs to.
+///
+/// Objects are copied to every store of the destination tier and each copy is
+/// verified before anything is deleted from the source tier. If a copy fails,
+/// the copies made so far are deleted again and the source is left untouched.
+/// Running the move again after a failed cleanup finishes it.
+pub async fn reclassify(
+    storage: &Storage,
+    publisher_user_id: &str,
+    video_id: &str,
+    direction: Direction,
+    progress: &Progress,
+) -> Result<Moved, ReclassifyError> {
+    let to_nsfw = direction.to_nsfw();
+    let from = storage.tier(!to_nsfw).tracked(progress);
+    let to = storage.tier(to_nsfw).tracked(progress);
+
+    let video_key = store::video_key(publisher_user_id, video_id);
+    let thumbnail_key = store::thumbnail_key(publisher_user_id, video_id);
+
+    let mut keys = vec![video_key.clone(), thumbnail_key];
+    keys.extend(from.list(&store::hls_key(video_id, "")).await?);
+
+    if from.locate(&video_key).await?.is_none() {
+        if to.locate(&video_key).await?.is_none() {
+            return Err(ReclassifyError::NotFound(video_key));
+        }
+
+        // An earlier move got as far as the cleanup
+        println!("{video_key} was moved already, removing leftovers from the source tier");
+        let objects = remove(&from, keys).await?;
+        return Ok(Moved {
+            objects,
+            already_moved: true,
+        });
+    }
+
+    let mut copied = Vec::with_capacity(keys.len());
+    for key in keys {
+        match copy(&from, &to, &key).await {
+            Ok(true) => copied.push(key),
+            // e.g. older videos without thumbnail
+            Ok(false) => {}
+            Err(source) => {
+                eprintln!("Copying {key} failed, rolling back: {source}");
+                copied.push(key.clone());
+                rollback(&to, &copied).await;
+                return Err(ReclassifyError::Copy { key, source });
+            }
+        }
+    }
+
+    let objects = remove(&from, copied).await?;
+
+    Ok(Moved {
+        objects,
+        already_moved: false,
+    })
+}
+
+/// Copy an object to every store of `to`, preserving its metadata, and verify the copies.
+///
+/// Returns `false` if no store of `from` has the object.
+pub async fn copy(from: &Tier, to: &Tier, key: &str) -> Result<bool, StoreError> {
+    let Some(source) = from.locate(key).await? else {
+        return Ok(false);
+    };
+    let info = source
+        .head(key)
+        .await?
+        .ok_or_else(|| StoreError::NotFound(key.to_string()))?;
+
+    // Stores in the same account as the source copy it server side. Those
+    // copies can only be verified against a checksum the source carries.
+    let stored = Checksum::stored(&info);
+    let copied = match stored {
+        Some(_) => to.copy_from(&source, key).await,
+        None => vec![],
+    };
+    let rest: Vec<_> = to
+        .backends()
+        .into_iter()
+        .filter(|backend| !copied.contains(backend))
+        .collect();
+
+    let checksum = match stored {
+        Some(checksum) if rest.is_empty() => checksum,
+        _ => stream(&source, &to.only(&rest), key, &info).await?,
+    };
+    to.verify(key, &checksum).await?;
+
+    Ok(true)
+}
+
+/// Stream an object from `source` to every store of `to`, preserving its metadata
+async fn stream(
+    source: &Arc<dyn ObjectStore>,
+    to: &Tier,
+    key: &str,
+    info: &ObjectInfo,
+) -> Result<Checksum, StoreError> {
+    let mut opts = PutOptions::for_key(key)
+        .with_metadata(info.metadata.clone())
+        .with_content_length(info.size);
+    if info.content_type.is_some() {
+        opts.content_type = info.content_type.clone();
+    }
+
+    let (body, checksum) = store::checksummed(source.get(key).await?, info.size);
+    to.put_stream(key, body, &opts).await?;
+    let checksum = checksum
+        .await
+        .map_err(|_| StoreError::Integrity(format!("{key} ended before it was fully read")))?;
+
+    // The source may carry a checksum from when it was uploaded
+    checksum
+        .verify(info)
+        .map_err(|e| StoreError::Integrity(format!("{} copy of {e}", source.name())))?;
+    Ok(checksum)
+}
+
+/// Delete the copies made by a move that failed
+async fn rollback(to: &Tier, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = to.delete(key).await {
+            eprintln!("Rolling back the copy of {key} failed, it has to be removed by hand: {e}");
+        }
+    }
+}
+
+/// Delete moved objects from every store of the source tier
+async fn remove(from: &Tier, keys: Vec<String>) -> Result<Vec<String>, ReclassifyError> {
+    for key in &keys {
+        from.delete(key)
+            .await
+            .map_err(|source| ReclassifyError::Cleanup {
+                key: key.clone(),
+                source,
+            })?;
+    }
+    Ok(keys)
+}
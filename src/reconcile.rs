@@ -0,0 +1,189 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::repair::{self, Repaired};
+use crate::store::{
+    self, integrity::SHA256_METADATA_KEY, ObjectInfo, Progress, Storage, StoreError, Tier,
+};
+
+/// How the copies of an object differ between the backends of a tier
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mismatch {
+    /// Some backends lack the object
+    Missing,
+    /// The copies differ in size
+    Size,
+    /// The copies carry different checksums
+    Checksum,
+    /// Only some of the copies carry a checksum
+    ChecksumMissing,
+}
+
+/// A copy of an object on one backend
+#[derive(Serialize, Debug)]
+pub struct Copy {
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// An object whose copies don't match
+#[derive(Serialize, Debug)]
+pub struct Drift {
+    pub key: String,
+    pub mismatch: Mismatch,
+    /// The copy on each backend, `null` where it is missing
+    pub backends: BTreeMap<&'static str, Option<Copy>>,
+}
+
+/// Outcome of checking the backends of the SFW tier against each other
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub publisher_user_id: String,
+    pub backends: Vec<&'static str>,
+    /// Number of objects compared
+    pub checked: usize,
+    pub mismatches: Vec<Drift>,
+    /// Outcome of repairing the mismatches, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repaired: Option<Vec<Repaired>>,
+}
+
+/// Compare what the backends of the SFW tier hold for a publisher: the mp4s
+/// and thumbnails under its directory key and the HLS trees of those videos.
+///
+/// Copies are compared by existence and size as listed, and by the checksum
+/// stored in their metadata. With `repair`, mismatching objects are copied
+/// from the backend holding a complete copy, see [`repair::repair`].
+pub async fn reconcile(
+    storage: &Storage,
+    publisher_user_id: &str,
+    repair: bool,
+    progress: &Progress,
+) -> Result<Report, StoreError> {
+    let tier = &storage.sfw;
+
+    let mut listed = Listed::new();
+    let prefix = format!("{publisher_user_id}/");
+    add(&mut listed, tier.list_each(&prefix).await?);
+    listed.retain(|key, _| store::video_id_of(publisher_user_id, key).is_some());
+
+    let video_ids: BTreeSet<String> = listed
+        .keys()
+        .filter_map(|key| store::video_id_of(publisher_user_id, key))
+        .map(str::to_string)
+        .collect();
+    for video_id in &video_ids {
+        add(
+            &mut listed,
+            tier.list_each(&store::hls_key(video_id, "")).await?,
+        );
+    }
+
+    let mut mismatches = vec![];
+    for (key, copies) in &listed {
+        if let Some(drift) = compare(tier, key, copies).await? {
+            mismatches.push(drift);
+        }
+    }
+    println!(
+        "Reconciled {} objects of {publisher_user_id}, {} mismatch",
+        listed.len(),
+        mismatches.len()
+    );
+
+    let repaired = if repair {
+        let keys: Vec<String> = mismatches.iter().map(|drift| drift.key.clone()).collect();
+        // Nothing tells which backend took the latest write here
+        let sources = BTreeMap::new();
+        Some(repair::repair(storage, false, &keys, &sources, progress).await?)
+    } else {
+        None
+    };
+
+    Ok(Report {
+        publisher_user_id: publisher_user_id.to_string(),
+        backends: tier.backends(),
+        checked: listed.len(),
+        mismatches,
+        repaired,
+    })
+}
+
+/// Key to the copy listed by each backend
+type Listed = BTreeMap<String, BTreeMap<&'static str, ObjectInfo>>;
+
+fn add(listed: &mut Listed, listings: Vec<(&'static str, Vec<ObjectInfo>)>) {
+    for (backend, objects) in listings {
+        for info in objects {
+            listed
+                .entry(info.key.clone())
+                .or_default()
+                .insert(backend, info);
+        }
+    }
+}
+
+/// Compare the listed copies of an object, looking up their checksums if the
+/// listing doesn't tell them apart
+async fn compare(
+    tier: &Tier,
+    key: &str,
+    listed: &BTreeMap<&'static str, ObjectInfo>,
+) -> Result<Option<Drift>, StoreError> {
+    let mut backends: BTreeMap<&'static str, Option<Copy>> = tier
+        .backends()
+        .into_iter()
+        .map(|backend| {
+            let copy = listed.get(backend).map(|info| Copy {
+                size: info.size,
+                sha256: None,
+            });
+            (backend, copy)
+        })
+        .collect();
+
+    let sizes: BTreeSet<_> = listed.values().map(|info| info.size).collect();
+    let mismatch = if listed.len() < backends.len() {
+        Some(Mismatch::Missing)
+    } else if sizes.len() > 1 {
+        Some(Mismatch::Size)
+    } else {
+        None
+    };
+    if let Some(mismatch) = mismatch {
+        return Ok(Some(Drift {
+            key: key.to_string(),
+            mismatch,
+            backends,
+        }));
+    }
+
+    for (backend, info) in tier.head_all(key).await {
+        let sha256 = info?.and_then(|info| info.metadata.get(SHA256_METADATA_KEY).cloned());
+        if let Some(Some(copy)) = backends.get_mut(backend) {
+            copy.sha256 = sha256;
+        }
+    }
+
+    let checksums: BTreeSet<_> = backends
+        .values()
+        .flatten()
+        .map(|copy| copy.sha256.as_deref())
+        .collect();
+    if checksums.len() <= 1 {
+        return Ok(None);
+    }
+    let mismatch = if checksums.contains(&None) {
+        Mismatch::ChecksumMissing
+    } else {
+        Mismatch::Checksum
+    };
+
+    Ok(Some(Drift {
+        key: key.to_string(),
+        mismatch,
+        backends,
+    }))
+}
@@ -0,0 +1,112 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::reclassify;
+use crate::store::{
+    integrity::SHA256_METADATA_KEY, ObjectInfo, Progress, PutOptions, Storage, StoreError,
+};
+
+/// Outcome of repairing one object
+#[derive(Serialize, Debug)]
+pub struct Repaired {
+    pub key: String,
+    /// Backend the healthy copy was taken from, `None` if no backend has the
+    /// object anymore
+    pub source: Option<&'static str>,
+    /// Backends the object was copied to
+    pub repaired: Vec<&'static str>,
+}
+
+/// Bring every store of a tier up to date with the objects at `keys`, after
+/// writes that only some of the stores took.
+///
+/// For each object, the copy on a store named in `sources` (i.e. one that took
+/// the write) is preferred as the source, then a copy carrying a checksum (one
+/// whose upload ran to the end). It is copied to every store that lacks the
+/// object or holds different content. Stores holding the same content with
+/// other metadata, e.g. after a failed metadata update, only get the metadata
+/// of the source.
+pub async fn repair(
+    storage: &Storage,
+    is_nsfw: bool,
+    keys: &[String],
+    sources: &BTreeMap<String, Vec<String>>,
+    progress: &Progress,
+) -> Result<Vec<Repaired>, StoreError> {
+    let tier = storage.tier(is_nsfw).tracked(progress);
+
+    let mut repaired = Vec::with_capacity(keys.len());
+    for key in keys {
+        let mut copies = vec![];
+        for (backend, info) in tier.head_all(key).await {
+            copies.push((backend, info?));
+        }
+
+        let took = sources.get(key);
+        let source = copies
+            .iter()
+            .filter_map(|(backend, info)| Some((*backend, info.as_ref()?)))
+            .min_by_key(|(backend, info)| {
+                let took_write = took.is_some_and(|took| took.iter().any(|b| b == backend));
+                (
+                    !took_write,
+                    !info.metadata.contains_key(SHA256_METADATA_KEY),
+                )
+            });
+        let Some((source, info)) = source else {
+            // e.g. deleted since
+            eprintln!("{key} exists on no backend anymore, nothing to repair");
+            repaired.push(Repaired {
+                key: key.clone(),
+                source: None,
+                repaired: vec![],
+            });
+            continue;
+        };
+
+        let mut stale = vec![];
+        let mut outdated = vec![];
+        for (backend, copy) in &copies {
+            match copy {
+                Some(copy) if same_version(copy, info) => {}
+                Some(copy) if same_content(copy, info) => outdated.push(*backend),
+                _ => stale.push(*backend),
+            }
+        }
+        if !stale.is_empty() {
+            println!("Repairing {key} on {} from {source}", stale.join(", "));
+            reclassify::copy(&tier.only(&[source]), &tier.only(&stale), key).await?;
+        }
+        if !outdated.is_empty() {
+            println!(
+                "Repairing metadata of {key} on {} from {source}",
+                outdated.join(", ")
+            );
+            let mut opts = PutOptions::for_key(key).with_metadata(info.metadata.clone());
+            if info.content_type.is_some() {
+                opts.content_type = info.content_type.clone();
+            }
+            tier.only(&outdated).set_metadata(key, &opts).await?;
+        }
+        stale.extend(outdated);
+
+        repaired.push(Repaired {
+            key: key.clone(),
+            source: Some(source),
+            repaired: stale,
+        });
+    }
+
+    Ok(repaired)
+}
+
+/// Whether two copies of an object hold the same content and metadata
+fn same_version(copy: &ObjectInfo, source: &ObjectInfo) -> bool {
+    copy.size == source.size && copy.metadata == source.metadata
+}
+
+/// Whether two copies of an object hold the same content, going by their checksums
+fn same_content(copy: &ObjectInfo, source: &ObjectInfo) -> bool {
+    let sha256 = |info: &ObjectInfo| info.metadata.get(SHA256_METADATA_KEY).cloned();
+    copy.size == source.size && sha256(copy).is_some() && sha256(copy) == sha256(source)
+}
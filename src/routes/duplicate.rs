@@ -1,788 +1,404 @@
-use axum::{body::Body, extract::State, response::IntoResponse, Json};
-use futures_util::StreamExt;
-use http_body_util::BodyExt;
-use reqwest::StatusCode;
-use serde::Deserialize;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::CONTENT_LENGTH, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 use std::process::Stdio;
-use storj_interface::duplicate::Args;
+use std::time::Duration;
+use storj_interface::callback::CallbackUrl;
+use storj_interface::duplicate::{Args, Source};
+use storj_interface::ids::{PublisherId, VideoId};
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
-use crate::consts::{ACCESS_GRANT_NSFW, ACCESS_GRANT_SFW, YRAL_NSFW_VIDEOS, YRAL_VIDEOS};
-use crate::s3_client::S3Client;
+use crate::error::Error;
+use crate::extract::{Json, Query};
+use crate::jobs::{Dispatch, JobKind, JobQueue};
+use crate::source::{self, Fetched, SourceError};
+use crate::store::{
+    self, ByteStream, Checksum, Consistency, Outcomes, Progress, PutOptions, Storage, StoreError,
+    Tier,
+};
 
 // TTL for pending uploads (in hours)
-const PENDING_UPLOAD_TTL_HOURS: u32 = 1;
-
-/// Extract a thumbnail from video data using ffmpeg
-/// Returns the PNG thumbnail bytes
-async fn extract_thumbnail(video_data: &[u8]) -> Result<Vec<u8>, Error> {
+pub const PENDING_UPLOAD_TTL_HOURS: u32 = 1;
+
+/// Metadata key marking a raw upload that wasn't finalized yet
+pub const PENDING_METADATA_KEY: &str = "_pending";
+/// Metadata key holding when a pending raw upload was made, in RFC 3339
+pub const UPLOADED_AT_METADATA_KEY: &str = "_uploaded_at";
+
+/// ffmpeg arguments grabbing the frame at 1 second
+const THUMBNAIL_AT_1S_ARGS: [&str; 11] = [
+    "-i",
+    "pipe:0", // Read from stdin
+    "-ss",
+    "00:00:01", // Seek to 1 second
+    "-vframes",
+    "1", // Extract 1 frame
+    "-f",
+    "image2pipe", // Output as image pipe
+    "-vcodec",
+    "png",    // PNG format
+    "pipe:1", // Write to stdout
+];
+
+/// ffmpeg arguments grabbing the very first frame, for videos shorter than a second
+const THUMBNAIL_FIRST_FRAME_ARGS: [&str; 9] = [
+    "-i",
+    "pipe:0",
+    "-vframes",
+    "1",
+    "-f",
+    "image2pipe",
+    "-vcodec",
+    "png",
+    "pipe:1",
+];
+
+/// Grab a single PNG frame from a video stream using ffmpeg
+///
+/// Returns `None` if ffmpeg couldn't produce a frame
+async fn grab_frame(args: &[&str], mut video: ByteStream) -> Result<Option<Vec<u8>>, Error> {
     let mut child = Command::new("ffmpeg")
-        .args([
-            "-i",
-            "pipe:0", // Read from stdin
-            "-ss",
-            "00:00:01", // Seek to 1 second (or first frame if shorter)
-            "-vframes",
-            "1", // Extract 1 frame
-            "-f",
-            "image2pipe", // Output as image pipe
-            "-vcodec",
-            "png",    // PNG format
-            "pipe:1", // Write to stdout
-        ])
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .spawn()?;
 
     let mut stdin = child.stdin.take().expect("Stdin pipe to be opened");
-    let video_data_clone = video_data.to_vec();
 
-    // Write video data to stdin in a separate task to avoid deadlock
+    // Write video data to stdin in a separate task to avoid deadlock.
+    // ffmpeg closes stdin as soon as it has its frame, which ends the task
+    // and drops our branch of the video stream.
     let write_task = tokio::spawn(async move {
-        stdin.write_all(&video_data_clone).await.ok();
-        drop(stdin);
+        while let Some(Ok(chunk)) = video.next().await {
+            if stdin.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
     });
 
     let output = child.wait_with_output().await?;
     write_task.await.ok();
 
     if !output.status.success() || output.stdout.is_empty() {
-        // Try again with frame 0 for very short videos
-        let mut child = Command::new("ffmpeg")
-            .args([
-                "-i",
-                "pipe:0",
-                "-vframes",
-                "1",
-                "-f",
-                "image2pipe",
-                "-vcodec",
-                "png",
-                "pipe:1",
-            ])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()?;
-
-        let mut stdin = child.stdin.take().expect("Stdin pipe to be opened");
-        let video_data_clone = video_data.to_vec();
-
-        let write_task = tokio::spawn(async move {
-            stdin.write_all(&video_data_clone).await.ok();
-            drop(stdin);
-        });
-
-        let output = child.wait_with_output().await?;
-        write_task.await.ok();
-
-        if output.stdout.is_empty() {
-            return Err(Error::Io(std::io::Error::other(
-                "Failed to extract thumbnail from video",
-            )));
-        }
-        return Ok(output.stdout);
+        return Ok(None);
     }
 
-    Ok(output.stdout)
+    Ok(Some(output.stdout))
 }
 
-/// Upload a thumbnail to Storj
-async fn upload_thumbnail_to_storj(
-    publisher_user_id: &str,
-    video_id: &str,
-    thumbnail_data: &[u8],
-    is_nsfw: bool,
-) -> Result<(), Error> {
-    let (bucket, grant) = if is_nsfw {
-        (YRAL_NSFW_VIDEOS.as_str(), ACCESS_GRANT_NSFW.as_str())
-    } else {
-        (YRAL_VIDEOS.as_str(), ACCESS_GRANT_SFW.as_str())
-    };
-    let dest = format!("sj://{bucket}/{publisher_user_id}/{video_id}_thumbnail.png");
-
-    let mut child = Command::new("uplink")
-        .args([
-            "cp",
-            "--interactive=false",
-            "--analytics=false",
-            "--progress=false",
-            "--access",
-            grant,
-            "-",
-            dest.as_str(),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    let mut pipe = child.stdin.take().expect("Stdin pipe to be opened for us");
-    pipe.write_all(thumbnail_data).await?;
-    pipe.flush().await?;
-    drop(pipe);
-
-    let status = child.wait().await?;
-    if !status.success() {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "uplink command failed with status: {status}"
-        ))));
-    }
-
-    Ok(())
-}
-
-/// Upload a thumbnail to Storj with TTL
-async fn upload_thumbnail_to_storj_with_ttl(
-    publisher_user_id: &str,
-    video_id: &str,
-    thumbnail_data: &[u8],
-    expires: &str,
-    is_nsfw: bool,
-) -> Result<(), Error> {
-    let (bucket, grant) = if is_nsfw {
-        (YRAL_NSFW_VIDEOS.as_str(), ACCESS_GRANT_NSFW.as_str())
-    } else {
-        (YRAL_VIDEOS.as_str(), ACCESS_GRANT_SFW.as_str())
-    };
-    let dest = format!("sj://{bucket}/{publisher_user_id}/{video_id}_thumbnail.png");
-
-    let mut child = Command::new("uplink")
-        .args([
-            "cp",
-            "--interactive=false",
-            "--analytics=false",
-            "--progress=false",
-            "--expires",
-            expires,
-            "--access",
-            grant,
-            "-",
-            dest.as_str(),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    let mut pipe = child.stdin.take().expect("Stdin pipe to be opened for us");
-    pipe.write_all(thumbnail_data).await?;
-    pipe.flush().await?;
-    drop(pipe);
-
-    let status = child.wait().await?;
-    if !status.success() {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "uplink command failed with status: {status}"
-        ))));
-    }
-
-    Ok(())
-}
-
-/// Upload a thumbnail to S3
-async fn upload_thumbnail_to_s3(
-    s3_client: &S3Client,
-    publisher_user_id: &str,
-    video_id: &str,
-    thumbnail_data: Vec<u8>,
-) -> Result<(), Error> {
-    let key = format!("{publisher_user_id}/{video_id}_thumbnail.png");
-
-    s3_client
-        .upload_thumbnail(&key, thumbnail_data)
-        .await
-        .map_err(|e| {
-            eprintln!("S3 thumbnail upload error for {publisher_user_id}/{video_id}: {e:?}");
-            Error::S3(format!("{e:?}"))
-        })?;
-
-    Ok(())
-}
-
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error(transparent)]
-    Network(#[from] reqwest::Error),
-
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-
-    #[error(transparent)]
-    Hyper(#[from] axum::Error),
-
-    #[error("Cloudflare returned non-ok status ({0}) when fetching the video")]
-    Clouflare(StatusCode),
-
-    #[error("S3 operation failed: {0}")]
-    S3(String),
-}
-
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        println!("err: {self}");
-        let (status, message) = match self {
-            Error::Network(_) | Error::Io(_) | Error::Hyper(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error. Check server logs.",
-            ),
-            Error::Clouflare(StatusCode::NOT_FOUND) => (
-                StatusCode::NOT_FOUND,
-                "The video doesn't exist on cloudflare",
-            ),
-            Error::Clouflare(_) => (
-                StatusCode::BAD_REQUEST,
-                "The video couldn't fetched from cloudflare. Check server logs.",
-            ),
-            Error::S3(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "S3 storage operation failed. Check server logs.",
-            ),
-        };
-
-        (
-            status,
-            Json(json!({
-                "message": message
-            })),
-        )
-            .into_response()
-    }
-}
-
-async fn upload_to_storj(
+/// Stream a video to every store of the tier while extracting its thumbnail
+/// on the fly, then upload the thumbnail next to it
+async fn upload_video_streaming(
+    tier: &Tier,
     publisher_user_id: &str,
     video_id: &str,
+    video: ByteStream,
+    content_length: Option<u64>,
     metadata: &BTreeMap<String, String>,
-    mut stream: impl futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
-    is_nsfw: bool,
-) -> Result<(), Error> {
-    let (bucket, grant) = if is_nsfw {
-        (YRAL_NSFW_VIDEOS.as_str(), ACCESS_GRANT_NSFW.as_str())
-    } else {
-        (YRAL_VIDEOS.as_str(), ACCESS_GRANT_SFW.as_str())
+    ttl: Option<Duration>,
+) -> Result<Checksum, Error> {
+    let video_key = store::video_key(publisher_user_id, video_id);
+    let video_opts = PutOptions::for_key(&video_key)
+        .with_metadata(metadata.clone())
+        .with_ttl(ttl)
+        .with_content_length(content_length);
+
+    // Hash the video on its way through. A stream that ends short of the
+    // announced length fails every upload instead of storing a truncated copy.
+    let (video, checksum) = store::checksummed(video, content_length);
+
+    let mut branches = store::fanout(video, 2);
+    let thumbnail_branch = branches.pop().expect("Branch for thumbnail extraction");
+    let upload_branch = branches.pop().expect("Branch for upload");
+
+    let (_, thumbnail_data) = tokio::try_join!(
+        async {
+            tier.put_stream(&video_key, upload_branch, &video_opts)
+                .await
+                .inspect_err(|e| eprintln!("Upload error for {video_key}: {e:?}"))
+                .map_err(Error::from)
+        },
+        grab_frame(&THUMBNAIL_AT_1S_ARGS, thumbnail_branch),
+    )?;
+
+    let thumbnail_data = match thumbnail_data {
+        Some(data) => data,
+        None => {
+            // Try again with frame 0 for very short videos, reading back the stored copy
+            let store = tier
+                .locate(&video_key)
+                .await?
+                .ok_or_else(|| StoreError::NotFound(video_key.clone()))?;
+            grab_frame(&THUMBNAIL_FIRST_FRAME_ARGS, store.get(&video_key).await?)
+                .await?
+                .ok_or_else(|| {
+                    Error::Io(std::io::Error::other(
+                        "Failed to extract thumbnail from video",
+                    ))
+                })?
+        }
     };
-    let dest = format!("sj://{bucket}/{publisher_user_id}/{video_id}.mp4");
-
-    let metadata_str = serde_json::to_string(metadata)
-        .expect("serialization to go through as we are guaranteed utf-8");
-
-    let mut child = Command::new("uplink")
-        .args([
-            "cp",
-            "--interactive=false",
-            "--analytics=false",
-            "--progress=false",
-            format!("--metadata={metadata_str}").as_str(),
-            "--access",
-            grant,
-            "-",
-            dest.as_str(),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .spawn()?;
-
-    let mut pipe = child.stdin.take().expect("Stdin pipe to be opened for us");
-
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        pipe.write_all(&chunk).await?;
-    }
 
-    drop(pipe);
-    let status = child.wait().await?;
-    if !status.success() {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "uplink command failed with status: {status}"
-        ))));
-    }
+    let thumbnail_key = store::thumbnail_key(publisher_user_id, video_id);
+    let thumbnail_opts = PutOptions::for_key(&thumbnail_key).with_ttl(ttl);
+    tier.put_bytes(&thumbnail_key, thumbnail_data.into(), &thumbnail_opts)
+        .await
+        .inspect_err(|e| eprintln!("Upload error for {thumbnail_key}: {e:?}"))?;
+
+    // The checksum is only known once the whole video went through, so it is
+    // attached to the stored copies afterwards, without writing them again
+    let checksum = checksum.await.map_err(|_| {
+        Error::Io(std::io::Error::other(format!(
+            "Video stream for {video_key} ended before it was fully read"
+        )))
+    })?;
+    tier.attach_checksum(&video_key, &checksum, &video_opts)
+        .await
+        .inspect_err(|e| eprintln!("Failed to store checksum of {video_key}: {e:?}"))?;
+    tier.verify(&video_key, &checksum)
+        .await
+        .inspect_err(|e| eprintln!("Stored copy of {video_key} is corrupt: {e:?}"))?;
 
-    Ok(())
+    Ok(checksum)
 }
 
-async fn upload_to_s3(
-    s3_client: &S3Client,
-    publisher_user_id: &str,
-    video_id: &str,
-    metadata: &BTreeMap<String, String>,
-    stream: impl futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>>,
-) -> Result<(), Error> {
-    let key = format!("{publisher_user_id}/{video_id}.mp4");
-
-    // Convert metadata to HashMap for S3
-    let mut s3_metadata = HashMap::new();
-    for (k, v) in metadata.iter() {
-        s3_metadata.insert(k.clone(), v.clone());
-    }
-
-    s3_client
-        .upload_video_stream(&key, stream, &s3_metadata)
-        .await
-        .map_err(|e| {
-            eprintln!("S3 upload error for {publisher_user_id}/{video_id}: {e:?}",);
-            Error::S3(format!("{e:?}"))
-        })?;
-
-    Ok(())
+/// Result of writing a video to its tier
+#[derive(Serialize, Debug)]
+pub struct Duplicated {
+    #[serde(flatten)]
+    pub checksum: Checksum,
+    #[serde(flatten)]
+    pub consistency: Consistency,
 }
 
-pub async fn handler(
-    State(s3_client): State<S3Client>,
-    Json(Args {
+/// Duplicate a video from its source into the tier it belongs to.
+///
+/// Stores of the tier that fail are recorded in `outcomes`, the video counts
+/// as duplicated once one store holds it.
+pub async fn run(
+    storage: &Storage,
+    args: Args,
+    progress: &Progress,
+    outcomes: &Outcomes,
+) -> Result<Checksum, Error> {
+    let Args {
         publisher_user_id,
         video_id,
         is_nsfw,
         metadata,
-    }): Json<Args>,
-) -> Result<impl IntoResponse, Error> {
-    let source = format!(
-        "https://customer-2p3jflss4r4hmpnz.cloudflarestream.com/{video_id}/downloads/default.mp4",
-    );
-
-    let req = reqwest::get(source).await?;
-    let status = req.status();
-
-    if status != StatusCode::OK {
-        return Err(Error::Clouflare(status));
+        source,
+        ..
+    } = args;
+
+    if source
+        == (Source::Object {
+            publisher_user_id: publisher_user_id.clone(),
+            video_id: video_id.clone(),
+            is_nsfw,
+        })
+    {
+        return Err(SourceError::Invalid("a video can't be duplicated onto itself".into()).into());
     }
 
-    // Collect all bytes into memory to extract thumbnail and upload
-    let body = req.bytes().await?;
-
-    // Extract thumbnail from video
-    let thumbnail_data = extract_thumbnail(&body).await?;
-
-    if !is_nsfw {
-        // For SFW videos, upload to both Storj and S3
-        let body_clone = body.clone();
-        let thumbnail_clone = thumbnail_data.clone();
-
-        // Create streams from the collected bytes
-        let storj_stream = futures_util::stream::once(async move { Ok(body) });
-        let s3_stream = futures_util::stream::once(async move { Ok(body_clone) });
-
-        let storj_video_upload = upload_to_storj(
-            &publisher_user_id,
-            &video_id,
-            &metadata,
-            Box::pin(storj_stream),
-            is_nsfw,
-        );
-        let storj_thumbnail_upload =
-            upload_thumbnail_to_storj(&publisher_user_id, &video_id, &thumbnail_data, is_nsfw);
-        let s3_video_upload = upload_to_s3(
-            &s3_client,
-            &publisher_user_id,
-            &video_id,
-            &metadata,
-            s3_stream,
-        );
-        let s3_thumbnail_upload =
-            upload_thumbnail_to_s3(&s3_client, &publisher_user_id, &video_id, thumbnail_clone);
-
-        tokio::try_join!(
-            storj_video_upload,
-            storj_thumbnail_upload,
-            s3_video_upload,
-            s3_thumbnail_upload
-        )?;
-    } else {
-        // For NSFW videos, only upload to Storj
-        let storj_stream = futures_util::stream::once(async move { Ok::<_, reqwest::Error>(body) });
-
-        let storj_video_upload = upload_to_storj(
-            &publisher_user_id,
-            &video_id,
-            &metadata,
-            Box::pin(storj_stream),
-            is_nsfw,
+    let source = source::resolve(&source, storage, &video_id);
+    let Fetched {
+        stream: video,
+        content_length,
+    } = source.open().await.inspect_err(|e| {
+        eprintln!(
+            "Failed to open {} source for {video_id}: {e}",
+            source.name()
         );
-        let storj_thumbnail_upload =
-            upload_thumbnail_to_storj(&publisher_user_id, &video_id, &thumbnail_data, is_nsfw);
+    })?;
+
+    // Stream the video straight through, never holding all of it in memory.
+    // SFW videos go to both Storj and S3, NSFW videos only to Storj.
+    upload_video_streaming(
+        &storage.tier(is_nsfw).tracked(progress).tolerant(outcomes),
+        &publisher_user_id,
+        &video_id,
+        video,
+        content_length,
+        &metadata,
+        None,
+    )
+    .await
+}
 
-        tokio::try_join!(storj_video_upload, storj_thumbnail_upload)?;
+pub async fn handler(
+    State(storage): State<Storage>,
+    State(jobs): State<JobQueue>,
+    Query(dispatch): Query<Dispatch>,
+    Json(args): Json<Args>,
+) -> Result<Response, Error> {
+    if dispatch.run_async || args.callback_url.is_some() {
+        return Ok(jobs
+            .enqueue(JobKind::Duplicate(args))
+            .await?
+            .into_response());
     }
 
-    Ok(())
+    let is_nsfw = args.is_nsfw;
+    let outcomes = Outcomes::default();
+    let checksum = run(&storage, args, &Progress::default(), &outcomes).await?;
+
+    Ok(Json(Duplicated {
+        checksum,
+        consistency: jobs.settle(is_nsfw, &outcomes).await?,
+    })
+    .into_response())
 }
 
 #[derive(Deserialize)]
 pub struct RawUploadInitialParams {
-    publisher_user_id: String,
-    video_id: String,
+    publisher_user_id: PublisherId,
+    video_id: VideoId,
     is_nsfw: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RawFinalizeParams {
-    publisher_user_id: String,
-    video_id: String,
-    is_nsfw: bool,
+    pub publisher_user_id: PublisherId,
+    pub video_id: VideoId,
+    pub is_nsfw: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RawFinalizeBody {
     #[serde(default)]
     metadata: BTreeMap<String, String>,
+    /// Url to POST the outcome to once finalized, implies running as a job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<CallbackUrl>,
 }
 
 pub async fn handler_raw_upload_initial(
-    State(s3_client): State<S3Client>,
-    axum::extract::Query(params): axum::extract::Query<RawUploadInitialParams>,
+    State(storage): State<Storage>,
+    Query(params): Query<RawUploadInitialParams>,
+    headers: HeaderMap,
     body: Body,
 ) -> Result<impl IntoResponse, Error> {
-    // Collect the body data
-    let body_data = body.collect().await.map_err(Error::Hyper)?.to_bytes();
-
-    // Extract thumbnail from video
-    let thumbnail_data = extract_thumbnail(&body_data).await?;
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse().ok());
+    let video = Box::pin(body.into_data_stream().map_err(std::io::Error::other));
 
     let mut pending_metadata = BTreeMap::new();
-    pending_metadata.insert("_pending".to_string(), "true".to_string());
-    pending_metadata.insert("_uploaded_at".to_string(), chrono::Utc::now().to_rfc3339());
-
-    let expires = format!("+{}h", PENDING_UPLOAD_TTL_HOURS);
-
-    if !params.is_nsfw {
-        // For SFW videos, upload to both Storj (with TTL) and S3 (without TTL)
-        let body_clone = body_data.clone();
-        let thumbnail_clone = thumbnail_data.clone();
-
-        let storj_video_upload = upload_to_storj_with_ttl(
-            &params.publisher_user_id,
-            &params.video_id,
-            &pending_metadata,
-            &body_data,
-            &expires,
-            params.is_nsfw,
-        );
-
-        let storj_thumbnail_upload = upload_thumbnail_to_storj_with_ttl(
-            &params.publisher_user_id,
-            &params.video_id,
-            &thumbnail_data,
-            &expires,
-            params.is_nsfw,
-        );
-
-        let s3_stream = futures_util::stream::once(async move { Ok(body_clone) });
-        let s3_video_upload = upload_to_s3(
-            &s3_client,
-            &params.publisher_user_id,
-            &params.video_id,
-            &pending_metadata,
-            s3_stream,
-        );
-
-        let s3_thumbnail_upload = upload_thumbnail_to_s3(
-            &s3_client,
-            &params.publisher_user_id,
-            &params.video_id,
-            thumbnail_clone,
-        );
-
-        tokio::try_join!(
-            storj_video_upload,
-            storj_thumbnail_upload,
-            s3_video_upload,
-            s3_thumbnail_upload
-        )?;
-    } else {
-        let storj_video_upload = upload_to_storj_with_ttl(
-            &params.publisher_user_id,
-            &params.video_id,
-            &pending_metadata,
-            &body_data,
-            &expires,
-            params.is_nsfw,
-        );
+    pending_metadata.insert(PENDING_METADATA_KEY.to_string(), "true".to_string());
+    pending_metadata.insert(
+        UPLOADED_AT_METADATA_KEY.to_string(),
+        chrono::Utc::now().to_rfc3339(),
+    );
 
-        let storj_thumbnail_upload = upload_thumbnail_to_storj_with_ttl(
-            &params.publisher_user_id,
-            &params.video_id,
-            &thumbnail_data,
-            &expires,
-            params.is_nsfw,
-        );
+    // Storj expires the pending upload after the TTL, S3 keeps it until finalized
+    let ttl = Duration::from_secs(PENDING_UPLOAD_TTL_HOURS as u64 * 60 * 60);
 
-        tokio::try_join!(storj_video_upload, storj_thumbnail_upload)?;
-    }
+    let checksum = upload_video_streaming(
+        storage.tier(params.is_nsfw),
+        &params.publisher_user_id,
+        &params.video_id,
+        video,
+        content_length,
+        &pending_metadata,
+        Some(ttl),
+    )
+    .await?;
 
     Ok(Json(json!({
         "status": "pending",
         "expires_in_hours": PENDING_UPLOAD_TTL_HOURS,
+        "sha256": checksum.sha256,
+        "size": checksum.size,
         "message": "Video uploaded successfully. Call /duplicate_raw/finalize to complete the upload."
     })))
 }
 
-pub async fn handler_raw_finalize(
-    State(s3_client): State<S3Client>,
-    axum::extract::Query(params): axum::extract::Query<RawFinalizeParams>,
-    Json(body): Json<RawFinalizeBody>,
-) -> Result<impl IntoResponse, Error> {
-    let metadata = body.metadata;
-
-    let (bucket, grant) = if params.is_nsfw {
-        (YRAL_NSFW_VIDEOS.as_str(), ACCESS_GRANT_NSFW.as_str())
-    } else {
-        (YRAL_VIDEOS.as_str(), ACCESS_GRANT_SFW.as_str())
-    };
-
-    let src_video_path = format!(
-        "sj://{}/{}/{}.mp4",
-        bucket, params.publisher_user_id, params.video_id
-    );
-    let src_thumbnail_path = format!(
-        "sj://{}/{}/{}_thumbnail.png",
-        bucket, params.publisher_user_id, params.video_id
-    );
-
-    // Download video to temporary file with retry logic
-    let temp_video_file = format!(
-        "/tmp/storj-finalize-{}-{}.mp4",
-        params.publisher_user_id, params.video_id
-    );
-    let temp_thumbnail_file = format!(
-        "/tmp/storj-finalize-{}-{}_thumbnail.png",
-        params.publisher_user_id, params.video_id
-    );
-
-    let max_retries = 3;
-    let retry_delay = std::time::Duration::from_secs(2);
-
-    // Download video
-    let mut last_error = String::new();
-    for attempt in 1..=max_retries {
-        let download_child = Command::new("uplink")
-            .args([
-                "cp",
-                "--interactive=false",
-                "--analytics=false",
-                "--progress=false",
-                "--access",
-                grant,
-                src_video_path.as_str(),
-                temp_video_file.as_str(),
-            ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        let output = download_child.wait_with_output().await?;
-
-        if output.status.success() {
-            break;
-        }
-
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        last_error = stderr.to_string();
-        eprintln!(
-            "Uplink video download attempt {}/{} failed: {}",
-            attempt, max_retries, stderr
-        );
-
-        if attempt < max_retries {
-            tokio::time::sleep(retry_delay).await;
-        }
-    }
-
-    // Check if video file exists after retries
-    if !tokio::fs::try_exists(&temp_video_file)
-        .await
-        .unwrap_or(false)
-    {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "Failed to download video from Storj after {} retries. Last error: {}",
-            max_retries, last_error
-        ))));
-    }
-
-    // Download thumbnail
-    let mut last_error = String::new();
-    for attempt in 1..=max_retries {
-        let download_child = Command::new("uplink")
-            .args([
-                "cp",
-                "--interactive=false",
-                "--analytics=false",
-                "--progress=false",
-                "--access",
-                grant,
-                src_thumbnail_path.as_str(),
-                temp_thumbnail_file.as_str(),
-            ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        let output = download_child.wait_with_output().await?;
-
-        if output.status.success() {
-            break;
-        }
-
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        last_error = stderr.to_string();
-        eprintln!(
-            "Uplink thumbnail download attempt {}/{} failed: {}",
-            attempt, max_retries, stderr
-        );
-
-        if attempt < max_retries {
-            tokio::time::sleep(retry_delay).await;
-        }
-    }
-
-    // Check if thumbnail file exists after retries
-    if !tokio::fs::try_exists(&temp_thumbnail_file)
+/// Turn a pending raw upload into a permanent one carrying its final metadata.
+///
+/// Only the metadata and expiry of the stored copies are replaced, which
+/// rewrites the storj copies. The checksum stored with the pending upload is
+/// carried over and checked against every copy.
+///
+/// Stores of the tier that fail are recorded in `outcomes`, like for [`run`].
+pub async fn run_finalize(
+    storage: &Storage,
+    params: RawFinalizeParams,
+    body: RawFinalizeBody,
+    progress: &Progress,
+    outcomes: &Outcomes,
+) -> Result<Checksum, Error> {
+    let tier = &storage.tier(params.is_nsfw).tracked(progress);
+
+    let video_key = store::video_key(&params.publisher_user_id, &params.video_id);
+    let thumbnail_key = store::thumbnail_key(&params.publisher_user_id, &params.video_id);
+
+    let info = tier
+        .locate(&video_key)
+        .await?
+        .ok_or_else(|| StoreError::NotFound(video_key.clone()))?
+        .head(&video_key)
+        .await?
+        .ok_or_else(|| StoreError::NotFound(video_key.clone()))?;
+    let checksum = Checksum::stored(&info).ok_or_else(|| {
+        StoreError::Integrity(format!(
+            "{video_key} carries no checksum to finalize it with"
+        ))
+    })?;
+
+    let mut metadata = body.metadata;
+    checksum.apply(&mut metadata);
+
+    // Without a TTL, the stores drop the expiry of the pending upload
+    let tier = tier.tolerant(outcomes);
+    let video_opts = PutOptions::for_key(&video_key).with_metadata(metadata);
+    let thumbnail_opts = PutOptions::for_key(&thumbnail_key);
+    tokio::try_join!(
+        tier.set_metadata(&video_key, &video_opts),
+        tier.set_metadata(&thumbnail_key, &thumbnail_opts),
+    )
+    .inspect_err(|e| eprintln!("Finalizing {video_key} failed: {e:?}"))?;
+    tier.verify(&video_key, &checksum)
         .await
-        .unwrap_or(false)
-    {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "Failed to download thumbnail from Storj after {} retries. Last error: {}",
-            max_retries, last_error
-        ))));
-    }
-
-    // Read the file data
-    let file_data = tokio::fs::read(&temp_video_file).await?;
-    let thumbnail_data = tokio::fs::read(&temp_thumbnail_file).await?;
-
-    // Re-upload with final metadata (no TTL)
-    if !params.is_nsfw {
-        // For SFW videos, upload to both Storj and S3
-        let file_data_clone = file_data.clone();
-        let thumbnail_clone = thumbnail_data.clone();
-
-        let storj_stream =
-            futures_util::stream::once(async move { Ok::<_, reqwest::Error>(file_data.into()) });
-        let s3_stream =
-            futures_util::stream::once(
-                async move { Ok::<_, reqwest::Error>(file_data_clone.into()) },
-            );
-
-        let storj_video_upload = upload_to_storj(
-            &params.publisher_user_id,
-            &params.video_id,
-            &metadata,
-            Box::pin(storj_stream),
-            params.is_nsfw,
-        );
-
-        let storj_thumbnail_upload = upload_thumbnail_to_storj(
-            &params.publisher_user_id,
-            &params.video_id,
-            &thumbnail_data,
-            params.is_nsfw,
-        );
-
-        let s3_video_upload = upload_to_s3(
-            &s3_client,
-            &params.publisher_user_id,
-            &params.video_id,
-            &metadata,
-            s3_stream,
-        );
-
-        let s3_thumbnail_upload = upload_thumbnail_to_s3(
-            &s3_client,
-            &params.publisher_user_id,
-            &params.video_id,
-            thumbnail_clone,
-        );
-
-        tokio::try_join!(
-            storj_video_upload,
-            storj_thumbnail_upload,
-            s3_video_upload,
-            s3_thumbnail_upload
-        )?;
-    } else {
-        // For NSFW videos, only upload to Storj
-        let storj_stream =
-            futures_util::stream::once(async move { Ok::<_, reqwest::Error>(file_data.into()) });
-
-        let storj_video_upload = upload_to_storj(
-            &params.publisher_user_id,
-            &params.video_id,
-            &metadata,
-            Box::pin(storj_stream),
-            params.is_nsfw,
-        );
+        .inspect_err(|e| eprintln!("Stored copy of {video_key} is corrupt: {e:?}"))?;
 
-        let storj_thumbnail_upload = upload_thumbnail_to_storj(
-            &params.publisher_user_id,
-            &params.video_id,
-            &thumbnail_data,
-            params.is_nsfw,
-        );
+    Ok(checksum)
+}
 
-        tokio::try_join!(storj_video_upload, storj_thumbnail_upload)?;
+pub async fn handler_raw_finalize(
+    State(storage): State<Storage>,
+    State(jobs): State<JobQueue>,
+    Query(params): Query<RawFinalizeParams>,
+    Query(dispatch): Query<Dispatch>,
+    Json(body): Json<RawFinalizeBody>,
+) -> Result<Response, Error> {
+    if dispatch.run_async || body.callback_url.is_some() {
+        return Ok(jobs
+            .enqueue(JobKind::Finalize { params, body })
+            .await?
+            .into_response());
     }
 
-    // Clean up temp files
-    tokio::fs::remove_file(&temp_video_file).await.ok();
-    tokio::fs::remove_file(&temp_thumbnail_file).await.ok();
+    let is_nsfw = params.is_nsfw;
+    let outcomes = Outcomes::default();
+    run_finalize(&storage, params, body, &Progress::default(), &outcomes).await?;
+    let consistency = jobs.settle(is_nsfw, &outcomes).await?;
 
     Ok(Json(json!({
         "status": "completed",
-        "message": "Video finalized successfully with metadata."
-    })))
-}
-
-async fn upload_to_storj_with_ttl(
-    publisher_user_id: &str,
-    video_id: &str,
-    metadata: &BTreeMap<String, String>,
-    body_data: &[u8],
-    expires: &str,
-    is_nsfw: bool,
-) -> Result<(), Error> {
-    let (bucket, grant) = if is_nsfw {
-        (YRAL_NSFW_VIDEOS.as_str(), ACCESS_GRANT_NSFW.as_str())
-    } else {
-        (YRAL_VIDEOS.as_str(), ACCESS_GRANT_SFW.as_str())
-    };
-    let dest = format!("sj://{bucket}/{publisher_user_id}/{video_id}.mp4");
-
-    let metadata_str = serde_json::to_string(metadata)
-        .expect("serialization to go through as we are guaranteed utf-8");
-
-    let mut child = Command::new("uplink")
-        .args([
-            "cp",
-            "--interactive=false",
-            "--analytics=false",
-            "--progress=false",
-            format!("--metadata={metadata_str}").as_str(),
-            "--expires",
-            expires,
-            "--access",
-            grant,
-            "-",
-            dest.as_str(),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    let mut pipe = child.stdin.take().expect("Stdin pipe to be opened for us");
-    pipe.write_all(body_data).await?;
-    pipe.flush().await?;
-    drop(pipe);
-
-    let status = child.wait().await?;
-    if !status.success() {
-        return Err(Error::Io(std::io::Error::other(format!(
-            "uplink command failed with status: {status}"
-        ))));
-    }
-
-    Ok(())
+        "message": "Video finalized successfully with metadata.",
+        "consistent": consistency.consistent,
+        "backends": consistency.backends,
+        "repair_job_id": consistency.repair_job_id,
+    }))
+    .into_response())
 }
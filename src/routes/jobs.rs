@@ -0,0 +1,13 @@
+use axum::extract::State;
+
+use crate::error::Error;
+use crate::extract::{Json, Path};
+use crate::jobs::{Job, JobQueue};
+
+/// Look up the state of a job
+pub async fn handler(
+    State(jobs): State<JobQueue>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, Error> {
+    jobs.get(&id).map(Json).ok_or(Error::JobNotFound(id))
+}
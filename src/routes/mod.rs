@@ -1,3 +1,9 @@
 pub mod duplicate;
 pub mod duplicate_hls;
+pub mod jobs;
 pub mod move2nsfw;
+pub mod move2sfw;
+pub mod publishers;
+pub mod reclassify;
+pub mod reconcile;
+pub mod videos;
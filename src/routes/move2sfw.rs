@@ -0,0 +1,18 @@
+use axum::{extract::State, response::Response};
+use storj_interface::move2sfw::Args;
+
+use crate::error::Error;
+use crate::extract::{Json, Query};
+use crate::jobs::{Dispatch, JobQueue};
+use crate::reclassify::Direction;
+use crate::store::Storage;
+
+/// Move a video with its thumbnail and HLS tree from the NSFW tier back to the SFW tier
+pub async fn handler(
+    State(storage): State<Storage>,
+    State(jobs): State<JobQueue>,
+    Query(dispatch): Query<Dispatch>,
+    Json(request): Json<Args>,
+) -> Result<Response, Error> {
+    super::reclassify::handle(&storage, &jobs, dispatch, Direction::ToSfw, request).await
+}
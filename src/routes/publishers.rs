@@ -0,0 +1,293 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use storj_interface::ids::PublisherId;
+
+use crate::error::Error;
+use crate::export;
+use crate::extract::{Json, Path, Query};
+use crate::jobs::{Accepted, JobKind, JobQueue};
+use crate::routes::duplicate::{PENDING_METADATA_KEY, UPLOADED_AT_METADATA_KEY};
+use crate::routes::videos::TIERS;
+use crate::store::{self, ObjectInfo, Storage, StoreError, Tier};
+
+/// Videos listed per page unless asked otherwise
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// Most videos listed per page, as each needs a listing of its HLS tree
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct PublisherPath {
+    pub publisher_user_id: PublisherId,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TierFilter {
+    Sfw,
+    Nsfw,
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// Only list videos of this tier
+    tier: Option<TierFilter>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// An object of a video, as listed by the backends of its tier
+#[derive(Serialize, Debug)]
+pub struct Asset {
+    pub size: Option<u64>,
+    pub backends: Vec<&'static str>,
+}
+
+/// The HLS tree of a video, as listed by the backends of its tier
+#[derive(Serialize, Debug)]
+pub struct HlsAsset {
+    pub files: usize,
+    /// Total size of the files in bytes
+    pub size: u64,
+    pub backends: Vec<&'static str>,
+}
+
+/// A video of a publisher in one tier
+#[derive(Serialize, Debug)]
+pub struct ListedVideo {
+    pub video_id: String,
+    pub tier: &'static str,
+    pub video: Option<Asset>,
+    pub thumbnail: Option<Asset>,
+    pub hls: Option<HlsAsset>,
+    /// When a pending raw upload was made
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploaded_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Listing {
+    pub publisher_user_id: PublisherId,
+    /// Finalized videos
+    pub videos: Vec<ListedVideo>,
+    /// Raw uploads that weren't finalized yet
+    pub pending: Vec<ListedVideo>,
+    /// Pass as `cursor` to get the next page, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Videos are listed ordered by id, then tier
+type Position = (String, &'static str);
+
+fn encode_cursor((video_id, tier): &Position) -> String {
+    hex::encode(format!("{video_id}/{tier}"))
+}
+
+fn decode_cursor(cursor: &str) -> Result<Position, Error> {
+    let invalid = || Error::invalid_input(format!("invalid cursor {cursor:?}"));
+    let decoded =
+        String::from_utf8(hex::decode(cursor).map_err(|_| invalid())?).map_err(|_| invalid())?;
+    let (video_id, tier) = decoded.split_once('/').ok_or_else(invalid)?;
+    let (tier, _) = TIERS
+        .into_iter()
+        .find(|(name, _)| *name == tier)
+        .ok_or_else(invalid)?;
+    Ok((video_id.to_string(), tier))
+}
+
+/// Add the copy of an object listed by `backend` to what is known of it
+fn add(asset: &mut Option<Asset>, backend: &'static str, info: &ObjectInfo) {
+    let asset = asset.get_or_insert(Asset {
+        size: info.size,
+        backends: vec![],
+    });
+    asset.backends.push(backend);
+}
+
+/// Mp4s and thumbnails of a publisher in a tier, grouped by video
+///
+/// Keys before those of `after` aren't listed, as the keys of every video
+/// sorting after it sort after them too. Some videos at or before `after`
+/// can still turn up, filtering those out is left to the caller.
+async fn list_videos(
+    tier: &Tier,
+    tier_name: &'static str,
+    publisher_user_id: &str,
+    after: Option<&Position>,
+    videos: &mut BTreeMap<Position, ListedVideo>,
+) -> Result<(), StoreError> {
+    let prefix = format!("{publisher_user_id}/");
+    let start_after = match after {
+        Some((video_id, _)) => format!("{prefix}{video_id}"),
+        None => prefix.clone(),
+    };
+    for (backend, objects) in tier.list_each_after(&prefix, &start_after).await? {
+        for info in objects {
+            let Some(video_id) = store::video_id_of(publisher_user_id, &info.key) else {
+                continue;
+            };
+            let video = videos
+                .entry((video_id.to_string(), tier_name))
+                .or_insert_with(|| ListedVideo {
+                    video_id: video_id.to_string(),
+                    tier: tier_name,
+                    video: None,
+                    thumbnail: None,
+                    hls: None,
+                    uploaded_at: None,
+                });
+            if info.key.ends_with(".mp4") {
+                add(&mut video.video, backend, &info);
+            } else {
+                add(&mut video.thumbnail, backend, &info);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the HLS tree of a listed video, and whether it is a pending upload
+async fn complete(
+    tier: &Tier,
+    publisher_user_id: &str,
+    video: &mut ListedVideo,
+) -> Result<bool, StoreError> {
+    let mut files = BTreeMap::new();
+    let mut backends = vec![];
+    for (backend, objects) in tier.list_each(&store::hls_key(&video.video_id, "")).await? {
+        if !objects.is_empty() {
+            backends.push(backend);
+        }
+        for info in objects {
+            files.entry(info.key).or_insert(info.size.unwrap_or(0));
+        }
+    }
+    if !files.is_empty() {
+        video.hls = Some(HlsAsset {
+            files: files.len(),
+            size: files.values().sum(),
+            backends,
+        });
+    }
+
+    // Only the metadata tells pending uploads apart
+    let Some(asset) = &video.video else {
+        return Ok(false);
+    };
+    let video_key = store::video_key(publisher_user_id, &video.video_id);
+    let info = tier.only(&asset.backends[..1]).head_all(&video_key).await;
+    let metadata = match info.into_iter().next() {
+        Some((_, Ok(Some(info)))) => info.metadata,
+        Some((_, Err(err))) => return Err(err),
+        _ => return Ok(false),
+    };
+    if metadata.get(PENDING_METADATA_KEY).map(String::as_str) != Some("true") {
+        return Ok(false);
+    }
+    video.uploaded_at = metadata
+        .get(UPLOADED_AT_METADATA_KEY)
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.to_utc());
+    Ok(true)
+}
+
+/// List the videos a publisher has stored, a page at a time
+pub async fn handler_list(
+    State(storage): State<Storage>,
+    Path(PublisherPath { publisher_user_id }): Path<PublisherPath>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Listing>, Error> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::invalid_input(format!(
+            "limit has to be between 1 and {MAX_PAGE_SIZE}"
+        )));
+    }
+    let after = params.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut found = BTreeMap::new();
+    for (name, is_nsfw) in TIERS {
+        let wanted = match params.tier {
+            Some(TierFilter::Sfw) => !is_nsfw,
+            Some(TierFilter::Nsfw) => is_nsfw,
+            None => true,
+        };
+        if wanted {
+            list_videos(
+                storage.tier(is_nsfw),
+                name,
+                &publisher_user_id,
+                after.as_ref(),
+                &mut found,
+            )
+            .await?;
+        }
+    }
+
+    let mut rest = found
+        .into_iter()
+        .filter(|(position, _)| after.as_ref().is_none_or(|after| position > after));
+    let page: Vec<_> = rest.by_ref().take(limit).collect();
+    let next_cursor = match (rest.next(), page.last()) {
+        (Some(_), Some((position, _))) => Some(encode_cursor(position)),
+        _ => None,
+    };
+
+    let mut videos = vec![];
+    let mut pending = vec![];
+    for (_, mut video) in page {
+        let tier = storage.tier(video.tier == "nsfw");
+        if complete(tier, &publisher_user_id, &mut video).await? {
+            pending.push(video);
+        } else {
+            videos.push(video);
+        }
+    }
+
+    Ok(Json(Listing {
+        publisher_user_id,
+        videos,
+        pending,
+        next_cursor,
+    }))
+}
+
+/// Queue the erasure of everything a publisher has stored, e.g. once its account is deleted
+pub async fn handler_erase(
+    State(jobs): State<JobQueue>,
+    Path(PublisherPath { publisher_user_id }): Path<PublisherPath>,
+) -> Result<Accepted, Error> {
+    println!("Queueing the erasure of {publisher_user_id}");
+    Ok(jobs.enqueue(JobKind::Erase { publisher_user_id }).await?)
+}
+
+/// Stream everything a publisher has stored as a tar archive: its videos and
+/// thumbnails in both tiers, along with a manifest of their metadata
+pub async fn handler_export(
+    State(storage): State<Storage>,
+    Path(PublisherPath { publisher_user_id }): Path<PublisherPath>,
+) -> Result<Response, Error> {
+    let export = export::prepare(&storage, &publisher_user_id).await?;
+    println!(
+        "Exporting {} objects of {publisher_user_id}",
+        export.objects()
+    );
+
+    let headers = [
+        (CONTENT_TYPE, "application/x-tar".to_string()),
+        (CONTENT_LENGTH, export.size().to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{publisher_user_id}.tar\""),
+        ),
+    ];
+    Ok((headers, Body::from_stream(export.stream())).into_response())
+}
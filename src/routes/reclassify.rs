@@ -0,0 +1,63 @@
+use axum::response::{IntoResponse, Response};
+use reqwest::StatusCode;
+use serde_json::json;
+use storj_interface::move2nsfw::Args;
+
+use crate::error::Error;
+use crate::extract::Json;
+use crate::jobs::{Dispatch, JobKind, JobQueue};
+use crate::reclassify::{self, Direction, Moved};
+use crate::store::{Progress, Storage};
+
+/// Move a video with its thumbnail and HLS tree to the tier of `direction`
+pub async fn run(
+    storage: &Storage,
+    direction: Direction,
+    request: Args,
+    progress: &Progress,
+) -> Result<Moved, Error> {
+    println!(
+        "Moving video {}/{} {direction}",
+        request.publisher_user_id, request.video_id
+    );
+
+    let moved = reclassify::reclassify(
+        storage,
+        &request.publisher_user_id,
+        &request.video_id,
+        direction,
+        progress,
+    )
+    .await?;
+
+    Ok(moved)
+}
+
+/// Shared by the move routes, which only differ in `direction`
+pub async fn handle(
+    storage: &Storage,
+    jobs: &JobQueue,
+    dispatch: Dispatch,
+    direction: Direction,
+    request: Args,
+) -> Result<Response, Error> {
+    if dispatch.run_async || request.callback_url.is_some() {
+        let job = match direction {
+            Direction::ToNsfw => JobKind::Move(request),
+            Direction::ToSfw => JobKind::MoveToSfw(request),
+        };
+        return Ok(jobs.enqueue(job).await?.into_response());
+    }
+
+    let moved = run(storage, direction, request, &Progress::default()).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "message": "moved",
+            "objects": moved.objects,
+            "already_moved": moved.already_moved,
+        })),
+    )
+        .into_response())
+}
@@ -0,0 +1,44 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use storj_interface::reconcile::Args;
+
+use crate::error::Error;
+use crate::extract::{Json, Query};
+use crate::jobs::{Dispatch, JobKind, JobQueue};
+use crate::reconcile::{self, Report};
+use crate::store::{Progress, Storage};
+
+/// Check the SFW backends against each other for everything a publisher has stored
+pub async fn run(storage: &Storage, args: Args, progress: &Progress) -> Result<Report, Error> {
+    println!(
+        "Reconciling the SFW backends for {}{}",
+        args.publisher_user_id,
+        if args.repair {
+            ", repairing mismatches"
+        } else {
+            ""
+        }
+    );
+
+    Ok(reconcile::reconcile(storage, &args.publisher_user_id, args.repair, progress).await?)
+}
+
+pub async fn handler(
+    State(storage): State<Storage>,
+    State(jobs): State<JobQueue>,
+    Query(dispatch): Query<Dispatch>,
+    Json(args): Json<Args>,
+) -> Result<Response, Error> {
+    if dispatch.run_async {
+        return Ok(jobs
+            .enqueue(JobKind::Reconcile(args))
+            .await?
+            .into_response());
+    }
+
+    let report = run(&storage, args, &Progress::default()).await?;
+
+    Ok(Json(report).into_response())
+}
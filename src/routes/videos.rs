@@ -0,0 +1,462 @@
+use axum::extract::State;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use storj_interface::ids::{PublisherId, VideoId};
+
+use crate::error::Error;
+use crate::extract::{Json, Path};
+use crate::jobs::JobQueue;
+use crate::routes::duplicate::{
+    PENDING_METADATA_KEY, PENDING_UPLOAD_TTL_HOURS, UPLOADED_AT_METADATA_KEY,
+};
+use crate::store::{
+    self, Consistency, ObjectInfo, Outcomes, PutOptions, Storage, StoreError, Tier,
+};
+
+/// Name of each tier in responses, with whether it is the nsfw one
+pub const TIERS: [(&str, bool); 2] = [("sfw", false), ("nsfw", true)];
+
+#[derive(Deserialize)]
+pub struct VideoPath {
+    publisher_user_id: PublisherId,
+    video_id: VideoId,
+}
+
+/// An object as one backend reports it
+#[derive(Serialize, Debug)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    /// Set for raw uploads that weren't finalized yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending: Option<Pending>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Pending {
+    pub uploaded_at: Option<DateTime<Utc>>,
+    /// When the backend drops the upload unless it is finalized, `None` if it keeps it
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl StoredObject {
+    fn of(info: ObjectInfo, expires_objects: bool) -> Self {
+        let pending = info
+            .metadata
+            .get(PENDING_METADATA_KEY)
+            .filter(|pending| *pending == "true")
+            .map(|_| {
+                let uploaded_at = info
+                    .metadata
+                    .get(UPLOADED_AT_METADATA_KEY)
+                    .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+                    .map(|at| at.to_utc());
+                let ttl = TimeDelta::hours(PENDING_UPLOAD_TTL_HOURS.into());
+                Pending {
+                    uploaded_at,
+                    expires_at: uploaded_at.filter(|_| expires_objects).map(|at| at + ttl),
+                }
+            });
+
+        Self {
+            key: info.key,
+            size: info.size,
+            content_type: info.content_type,
+            metadata: info.metadata,
+            pending,
+        }
+    }
+}
+
+/// What one backend holds of a video
+#[derive(Serialize, Debug, Default)]
+pub struct BackendView {
+    pub video: Option<StoredObject>,
+    pub thumbnail: Option<StoredObject>,
+    pub hls_master: Option<StoredObject>,
+    /// Why the backend couldn't be checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BackendView {
+    fn is_empty(&self) -> bool {
+        self.video.is_none() && self.thumbnail.is_none() && self.hls_master.is_none()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct VideoView {
+    pub publisher_user_id: PublisherId,
+    pub video_id: VideoId,
+    /// Tier name to what each of its backends holds
+    pub tiers: BTreeMap<&'static str, BTreeMap<&'static str, BackendView>>,
+}
+
+/// Look up the mp4, thumbnail and HLS master of a video on every backend of a tier
+async fn inspect(
+    tier: &Tier,
+    publisher_user_id: &str,
+    video_id: &str,
+) -> BTreeMap<&'static str, BackendView> {
+    let video_key = store::video_key(publisher_user_id, video_id);
+    let thumbnail_key = store::thumbnail_key(publisher_user_id, video_id);
+    let hls_master_key = store::hls_key(video_id, "master.m3u8");
+
+    let (videos, thumbnails, hls_masters) = tokio::join!(
+        tier.head_all(&video_key),
+        tier.head_all(&thumbnail_key),
+        tier.head_all(&hls_master_key),
+    );
+
+    // head_all reports the backends in the same order for every key
+    videos
+        .into_iter()
+        .zip(thumbnails)
+        .zip(hls_masters)
+        .map(|(((backend, video), (_, thumbnail)), (_, hls_master))| {
+            let mut error = None;
+            let mut found = |info: Result<Option<ObjectInfo>, StoreError>| match info {
+                Ok(info) => info.map(|info| StoredObject::of(info, tier.expires_objects(backend))),
+                Err(err) => {
+                    eprintln!("Couldn't look up {video_id} on {backend}: {err}");
+                    error.get_or_insert(err.to_string());
+                    None
+                }
+            };
+            let video = found(video);
+            let thumbnail = found(thumbnail);
+            let hls_master = found(hls_master);
+            let view = BackendView {
+                video,
+                thumbnail,
+                hls_master,
+                error,
+            };
+            (backend, view)
+        })
+        .collect()
+}
+
+/// Report what every backend of both tiers holds of a video
+pub async fn handler(
+    State(storage): State<Storage>,
+    Path(VideoPath {
+        publisher_user_id,
+        video_id,
+    }): Path<VideoPath>,
+) -> Result<Json<VideoView>, Error> {
+    let mut tiers = BTreeMap::new();
+    for (name, is_nsfw) in TIERS {
+        let backends = inspect(storage.tier(is_nsfw), &publisher_user_id, &video_id).await;
+        tiers.insert(name, backends);
+    }
+
+    let nothing_found = tiers
+        .values()
+        .flat_map(BTreeMap::values)
+        .all(|view| view.is_empty() && view.error.is_none());
+    if nothing_found {
+        return Err(StoreError::NotFound(store::video_key(&publisher_user_id, &video_id)).into());
+    }
+
+    Ok(Json(VideoView {
+        publisher_user_id,
+        video_id,
+        tiers,
+    }))
+}
+
+/// What became of an object on one backend
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Removal {
+    Deleted,
+    /// The backend didn't hold the object (anymore)
+    Absent,
+    /// Left alone, as the HLS tree of the video id doesn't belong to the publisher
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RemovedObject {
+    pub tier: &'static str,
+    pub backend: &'static str,
+    pub key: String,
+    pub result: Removal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RemovedObject {
+    fn of(
+        tier: &'static str,
+        backend: &'static str,
+        key: String,
+        result: Result<Removal, StoreError>,
+    ) -> Self {
+        let (result, error) = match result {
+            Ok(removal) => (removal, None),
+            Err(err) => {
+                eprintln!("Couldn't delete {key} from {backend}: {err}");
+                (Removal::Failed, Some(err.to_string()))
+            }
+        };
+        Self {
+            tier,
+            backend,
+            key,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Deletion {
+    pub publisher_user_id: PublisherId,
+    pub video_id: VideoId,
+    /// Whether no backend holds anything of the video anymore
+    pub complete: bool,
+    pub objects: Vec<RemovedObject>,
+}
+
+/// Whether a single store holds an object
+async fn exists(store: &Tier, key: &str) -> Result<bool, StoreError> {
+    let found = store
+        .head_all(key)
+        .await
+        .into_iter()
+        .next()
+        .map(|(_, info)| info)
+        .transpose()?
+        .flatten();
+    Ok(found.is_some())
+}
+
+/// Delete the mp4, thumbnail and HLS tree of a video from every backend of a tier.
+///
+/// HLS keys aren't scoped to the publisher, so a backend's HLS tree is only
+/// deleted if the backend holds the publisher's mp4 or thumbnail. The tree
+/// goes first and those are kept if it fails, so a retry still finds it to be
+/// the publisher's.
+async fn remove_from(
+    tier: &Tier,
+    tier_name: &'static str,
+    publisher_user_id: &str,
+    video_id: &str,
+) -> Vec<RemovedObject> {
+    let mut removed = vec![];
+    for backend in tier.backends() {
+        let store = tier.only(&[backend]);
+        let mut found = vec![];
+        for key in [
+            store::video_key(publisher_user_id, video_id),
+            store::thumbnail_key(publisher_user_id, video_id),
+        ] {
+            match exists(&store, &key).await {
+                Ok(true) => found.push(key),
+                result => {
+                    let result = result.map(|_| Removal::Absent);
+                    removed.push(RemovedObject::of(tier_name, backend, key, result));
+                }
+            }
+        }
+
+        let hls_prefix = store::hls_key(video_id, "");
+        let mut hls_removed = true;
+        match store.list(&hls_prefix).await {
+            Ok(hls_keys) => {
+                for key in hls_keys {
+                    let result = if found.is_empty() {
+                        Ok(Removal::Skipped)
+                    } else {
+                        store.delete(&key).await.map(|()| Removal::Deleted)
+                    };
+                    hls_removed &= result.is_ok();
+                    removed.push(RemovedObject::of(tier_name, backend, key, result));
+                }
+            }
+            Err(err) => {
+                hls_removed = false;
+                removed.push(RemovedObject::of(tier_name, backend, hls_prefix, Err(err)));
+            }
+        }
+
+        for key in found {
+            if !hls_removed {
+                removed.push(RemovedObject {
+                    tier: tier_name,
+                    backend,
+                    key,
+                    result: Removal::Failed,
+                    error: Some("kept until the video's HLS tree is deleted".into()),
+                });
+                continue;
+            }
+            let result = store.delete(&key).await.map(|()| Removal::Deleted);
+            removed.push(RemovedObject::of(tier_name, backend, key, result));
+        }
+    }
+    removed
+}
+
+/// Delete everything stored of a video from every backend of both tiers.
+/// Deleting a video that is gone already succeeds, reporting its objects as absent.
+/// If any deletion failed, the request fails with the same report.
+pub async fn handler_delete(
+    State(storage): State<Storage>,
+    Path(VideoPath {
+        publisher_user_id,
+        video_id,
+    }): Path<VideoPath>,
+) -> Result<Json<Deletion>, Error> {
+    let mut objects = vec![];
+    for (name, is_nsfw) in TIERS {
+        objects
+            .extend(remove_from(storage.tier(is_nsfw), name, &publisher_user_id, &video_id).await);
+    }
+
+    let deleted = objects
+        .iter()
+        .filter(|object| object.result == Removal::Deleted)
+        .count();
+    let failed: Vec<&RemovedObject> = objects
+        .iter()
+        .filter(|object| object.result == Removal::Failed)
+        .collect();
+    let backend = failed.first().map(|object| object.backend);
+    let failed = failed.len();
+    let complete = failed == 0;
+    println!(
+        "Deleted {deleted} objects of {publisher_user_id}/{video_id}{}",
+        if complete {
+            ""
+        } else {
+            ", some deletions failed"
+        }
+    );
+
+    let deletion = Deletion {
+        publisher_user_id,
+        video_id,
+        complete,
+        objects,
+    };
+    if !complete {
+        return Err(Error::DeleteIncomplete {
+            backend,
+            failed,
+            report: serde_json::to_value(&deletion).expect("deletions to be serializable"),
+        });
+    }
+
+    Ok(Json(deletion))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MetadataPatch {
+    /// Keys to set, or to remove when `null`. Other keys are kept as they are.
+    pub metadata: BTreeMap<String, Option<String>>,
+}
+
+/// The metadata a tier holds of a video after an update
+#[derive(Serialize, Debug)]
+pub struct UpdatedTier {
+    pub metadata: BTreeMap<String, String>,
+    pub consistency: Consistency,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MetadataUpdate {
+    pub publisher_user_id: PublisherId,
+    pub video_id: VideoId,
+    /// Tier name to the metadata now stored there, for the tiers holding the video
+    pub tiers: BTreeMap<&'static str, UpdatedTier>,
+}
+
+/// Update the metadata of a video on every backend of the tiers holding it.
+/// S3 updates it in place, storj rewrites the object (see [`crate::store::ObjectStore::set_metadata`]).
+///
+/// Keys starting with `_` are kept by the service (checksums, upload state)
+/// and can't be changed this way. Pending raw uploads are finalized instead.
+pub async fn handler_update_metadata(
+    State(storage): State<Storage>,
+    State(jobs): State<JobQueue>,
+    Path(VideoPath {
+        publisher_user_id,
+        video_id,
+    }): Path<VideoPath>,
+    Json(patch): Json<MetadataPatch>,
+) -> Result<Json<MetadataUpdate>, Error> {
+    if let Some(key) = patch.metadata.keys().find(|key| key.starts_with('_')) {
+        return Err(Error::invalid_input(format!(
+            "metadata key {key} is reserved, keys starting with _ can't be changed"
+        )));
+    }
+
+    let video_key = store::video_key(&publisher_user_id, &video_id);
+    let mut tiers = BTreeMap::new();
+    for (name, is_nsfw) in TIERS {
+        let tier = storage.tier(is_nsfw);
+        // Backends may have diverged, the first one holding the video is the reference
+        let Some(store) = tier.locate(&video_key).await? else {
+            continue;
+        };
+        // e.g. deleted since it was located
+        let Some(current) = store.head(&video_key).await? else {
+            continue;
+        };
+        if current
+            .metadata
+            .get(PENDING_METADATA_KEY)
+            .is_some_and(|pending| pending == "true")
+        {
+            return Err(Error::invalid_input(format!(
+                "{video_key} is a pending raw upload, finalize it instead"
+            )));
+        }
+
+        let mut metadata = current.metadata;
+        for (key, value) in &patch.metadata {
+            match value {
+                Some(value) => metadata.insert(key.clone(), value.clone()),
+                None => metadata.remove(key),
+            };
+        }
+
+        let mut opts = PutOptions::for_key(&video_key).with_metadata(metadata.clone());
+        if current.content_type.is_some() {
+            opts.content_type = current.content_type;
+        }
+        let outcomes = Outcomes::default();
+        tier.tolerant(&outcomes)
+            .set_metadata(&video_key, &opts)
+            .await
+            .inspect_err(|e| eprintln!("Updating metadata of {video_key} failed: {e:?}"))?;
+        let consistency = jobs.settle(is_nsfw, &outcomes).await?;
+
+        tiers.insert(
+            name,
+            UpdatedTier {
+                metadata,
+                consistency,
+            },
+        );
+    }
+
+    if tiers.is_empty() {
+        return Err(StoreError::NotFound(video_key).into());
+    }
+    println!("Updated metadata of {video_key} in {} tiers", tiers.len());
+
+    Ok(Json(MetadataUpdate {
+        publisher_user_id,
+        video_id,
+        tiers,
+    }))
+}
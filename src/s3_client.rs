@@ -1,20 +1,94 @@
+use async_trait::async_trait;
 use aws_config::BehaviorVersion;
-use aws_sdk_s3::config::{Credentials, Region};
-use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::config::{retry::RetryConfig, Credentials, Region, RequestChecksumCalculation};
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, Delete, MetadataDirective, ObjectIdentifier,
+};
 use aws_sdk_s3::{Client, Config};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_util::StreamExt;
-use std::collections::HashMap;
+use http_body::Frame;
+use http_body_util::StreamBody;
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::ReceiverStream;
 
 use crate::consts::{
-    HETZNER_S3_ACCESS_KEY, HETZNER_S3_BUCKET, HETZNER_S3_ENDPOINT, HETZNER_S3_REGION,
-    HETZNER_S3_SECRET_KEY,
+    HETZNER_S3_ACCESS_KEY, HETZNER_S3_BUCKET, HETZNER_S3_ENDPOINT,
+    HETZNER_S3_MULTIPART_CONCURRENCY, HETZNER_S3_MULTIPART_PART_SIZE_MB,
+    HETZNER_S3_MULTIPART_THRESHOLD_MB, HETZNER_S3_REGION, HETZNER_S3_SECRET_KEY,
 };
+use crate::store::{self, Bucket, ByteStream, ObjectInfo, ObjectStore, PutOptions, StoreError};
+
+/// Most keys a single `DeleteObjects` request may name
+const DELETE_BATCH_SIZE: usize = 1000;
+
+/// When and how uploads are split into multipart uploads
+#[derive(Clone, Copy, Debug)]
+pub struct MultipartConfig {
+    /// Bodies of known size at or above this many bytes use multipart uploads
+    pub threshold: u64,
+    /// Size of each part in bytes
+    pub part_size: usize,
+    /// Number of parts uploaded at the same time
+    pub concurrency: usize,
+}
+
+/// Aborts a multipart upload that wasn't completed, so its parts aren't
+/// kept (and billed) forever. This includes uploads whose future is dropped,
+/// e.g. when the client of a request goes away.
+struct MultipartGuard {
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    completed: bool,
+}
+
+impl Drop for MultipartGuard {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            eprintln!(
+                "Couldn't abort multipart upload {} of {}, no runtime left",
+                self.upload_id, self.key
+            );
+            return;
+        };
+        let abort = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id);
+        let (key, upload_id) = (self.key.clone(), self.upload_id.clone());
+        runtime.spawn(async move {
+            if let Err(e) = abort.send().await {
+                eprintln!("Failed to abort multipart upload {upload_id} of {key}: {e:?}");
+            }
+        });
+    }
+}
+
+impl MultipartConfig {
+    fn from_env() -> Self {
+        const MB: u64 = 1024 * 1024;
+        Self {
+            threshold: *HETZNER_S3_MULTIPART_THRESHOLD_MB * MB,
+            part_size: (*HETZNER_S3_MULTIPART_PART_SIZE_MB * MB) as usize,
+            concurrency: *HETZNER_S3_MULTIPART_CONCURRENCY,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct S3Client {
     client: Client,
     bucket: String,
+    multipart: MultipartConfig,
 }
 
 impl S3Client {
@@ -33,6 +107,11 @@ impl S3Client {
             .endpoint_url(HETZNER_S3_ENDPOINT.as_str())
             .credentials_provider(creds)
             .force_path_style(true)
+            // Default checksums need the body size up front, which streamed bodies
+            // don't report, and aren't supported by every S3 compatible store
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            // Retried by the tier wrapping this client, the same as every other backend
+            .retry_config(RetryConfig::disabled())
             .build();
 
         let client = Client::from_conf(config);
@@ -40,72 +119,316 @@ impl S3Client {
         Self {
             client,
             bucket: HETZNER_S3_BUCKET.clone(),
+            multipart: MultipartConfig::from_env(),
         }
     }
 
-    pub async fn upload_video_stream(
-        &self,
-        key: &str,
-        stream: impl futures_util::Stream<Item = Result<Bytes, reqwest::Error>>,
-        metadata: &HashMap<String, String>,
-    ) -> Result<(), aws_sdk_s3::Error> {
-        let chunks: Vec<Bytes> = stream
-            .filter_map(|chunk| async move { chunk.ok() })
-            .collect()
-            .await;
-
-        let body_bytes = chunks.concat();
-        let body = ByteStream::from(body_bytes);
-
+    fn put_object(&self, key: &str, opts: &PutOptions) -> PutObjectFluentBuilder {
         let mut request = self
             .client
             .put_object()
             .bucket(&self.bucket)
             .key(key)
-            .body(body)
-            .content_type("video/mp4");
+            .set_content_type(opts.content_type.clone());
 
         // Add metadata
-        for (k, v) in metadata {
+        for (k, v) in &opts.metadata {
             request = request.metadata(k, v);
         }
 
-        request.send().await?;
-        Ok(())
+        request
     }
 
-    pub async fn upload_hls_segment(
+    /// Single request upload, streamed if the size is known up front
+    async fn put_single(
+        &self,
+        key: &str,
+        body: ByteStream,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        // S3 needs the length of a streamed body up front, otherwise buffer it.
+        // A streamed body can only be sent once, so it isn't retried.
+        match opts.content_length {
+            Some(len) => {
+                self.put_object(key, &opts)
+                    .body(streaming_body(body))
+                    .content_length(len as i64)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+                Ok(())
+            }
+            None => {
+                self.put_buffered(key, store::collect(body).await?, opts)
+                    .await
+            }
+        }
+    }
+
+    /// Single request upload of a body in memory
+    async fn put_buffered(
         &self,
         key: &str,
         data: Bytes,
-        metadata: &HashMap<String, String>,
-    ) -> Result<(), aws_sdk_s3::Error> {
-        let content_type = if key.ends_with(".m3u8") {
-            "application/vnd.apple.mpegurl"
-        } else if key.ends_with(".ts") {
-            "video/mp2t"
-        } else {
-            "application/octet-stream"
-        };
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        self.put_object(key, &opts)
+            .body(data.into())
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
 
-        let mut request = self
+    /// Upload in parts of `multipart.part_size`, keeping at most
+    /// `multipart.concurrency` parts in flight (and in memory).
+    ///
+    /// Bodies that turn out to be smaller than a single part are sent with a
+    /// plain `put_object` instead. On failure the multipart upload is aborted
+    /// so no orphaned parts are left behind in the bucket.
+    async fn put_multipart(
+        &self,
+        key: &str,
+        mut body: ByteStream,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        let part_size = self.multipart.part_size;
+
+        let mut first_part = BytesMut::with_capacity(part_size);
+        while first_part.len() < part_size {
+            match body.next().await {
+                Some(chunk) => first_part.extend_from_slice(&chunk?),
+                None => return self.put_buffered(key, first_part.freeze(), opts).await,
+            }
+        }
+
+        let upload = self
             .client
-            .put_object()
+            .create_multipart_upload()
             .bucket(&self.bucket)
             .key(key)
-            .body(ByteStream::from(data))
-            .content_type(content_type);
+            .set_content_type(opts.content_type.clone())
+            .set_metadata(Some(opts.metadata.clone().into_iter().collect()))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let upload_id = upload.upload_id.ok_or_else(|| {
+            StoreError::S3("create_multipart_upload returned no upload id".into())
+        })?;
 
-        // Add metadata
-        for (k, v) in metadata {
-            request = request.metadata(k, v);
-        }
+        let mut guard = MultipartGuard {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.clone(),
+            completed: false,
+        };
+
+        let parts = self
+            .upload_parts(key, &upload_id, first_part, body)
+            .await
+            .inspect_err(|e| eprintln!("Multipart upload of {key} failed, aborting: {e}"))?;
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(s3_error)
+            .inspect_err(|e| eprintln!("Multipart upload of {key} failed, aborting: {e}"))?;
+        guard.completed = true;
 
-        request.send().await?;
         Ok(())
     }
 
-    pub async fn download_video(&self, key: &str) -> Result<Vec<u8>, String> {
+    /// Objects starting with `prefix`, only those sorting after `start_after`
+    /// if given, which S3 skips without listing them
+    async fn list_objects(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+    ) -> Result<Vec<ObjectInfo>, StoreError> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .set_start_after(start_after.map(str::to_string))
+            .into_paginator()
+            .send();
+
+        let mut objects = vec![];
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            objects.extend(
+                page.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|obj| {
+                        Some(ObjectInfo {
+                            key: obj.key?,
+                            size: obj.size.and_then(|size| size.try_into().ok()),
+                            content_type: None,
+                            metadata: Default::default(),
+                        })
+                    }),
+            );
+        }
+
+        Ok(objects)
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut buffer: BytesMut,
+        mut body: ByteStream,
+    ) -> Result<Vec<CompletedPart>, StoreError> {
+        let part_size = self.multipart.part_size;
+        let mut in_flight = JoinSet::new();
+        let mut parts = vec![];
+        let mut part_number = 1;
+        let mut body_done = false;
+
+        while !body_done || !buffer.is_empty() {
+            while !body_done && buffer.len() < part_size {
+                match body.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None => body_done = true,
+                }
+            }
+            if buffer.is_empty() {
+                break;
+            }
+
+            let part = buffer.split_to(buffer.len().min(part_size)).freeze();
+            in_flight.spawn(upload_part(
+                self.client.clone(),
+                self.bucket.clone(),
+                key.to_string(),
+                upload_id.to_string(),
+                part_number,
+                part,
+            ));
+            part_number += 1;
+
+            while in_flight.len() >= self.multipart.concurrency {
+                parts.push(join_part(&mut in_flight).await?);
+            }
+        }
+
+        while !in_flight.is_empty() {
+            parts.push(join_part(&mut in_flight).await?);
+        }
+
+        parts.sort_by_key(|part| part.part_number);
+        Ok(parts)
+    }
+}
+
+/// Upload a single part
+async fn upload_part(
+    client: Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    part_number: i32,
+    data: Bytes,
+) -> Result<CompletedPart, StoreError> {
+    let resp = client
+        .upload_part()
+        .bucket(&bucket)
+        .key(&key)
+        .upload_id(&upload_id)
+        .part_number(part_number)
+        .content_length(data.len() as i64)
+        .body(data.into())
+        .send()
+        .await
+        .map_err(s3_error)?;
+
+    Ok(CompletedPart::builder()
+        .part_number(part_number)
+        .set_e_tag(resp.e_tag)
+        .build())
+}
+
+async fn join_part(
+    in_flight: &mut JoinSet<Result<CompletedPart, StoreError>>,
+) -> Result<CompletedPart, StoreError> {
+    in_flight
+        .join_next()
+        .await
+        .expect("Part uploads to be in flight")
+        .map_err(|e| StoreError::Io(std::io::Error::other(e)))?
+}
+
+/// Adapt a [`ByteStream`] into a body the sdk can send without buffering it
+fn streaming_body(mut body: ByteStream) -> aws_sdk_s3::primitives::ByteStream {
+    // The sdk requires a `Sync` body, so hand the chunks over through a channel
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        while let Some(chunk) = body.next().await {
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let frames = ReceiverStream::new(rx).map(|chunk| chunk.map(Frame::data));
+    aws_sdk_s3::primitives::ByteStream::from_body_1_x(StreamBody::new(frames))
+}
+
+fn s3_error<E, R>(err: SdkError<E, R>) -> StoreError
+where
+    aws_sdk_s3::Error: From<SdkError<E, R>>,
+{
+    let timed_out = match &err {
+        SdkError::TimeoutError(_) => true,
+        SdkError::DispatchFailure(failure) => failure.is_timeout(),
+        _ => false,
+    };
+    let message = format!("{:?}", aws_sdk_s3::Error::from(err));
+    if timed_out {
+        StoreError::S3Timeout(message)
+    } else {
+        StoreError::S3(message)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Client {
+    fn name(&self) -> &'static str {
+        "hetzner_s3"
+    }
+
+    fn expires_objects(&self) -> bool {
+        // Hetzner doesn't support object expiry, so `opts.ttl` is ignored
+        false
+    }
+
+    fn bucket(&self) -> Bucket {
+        Bucket {
+            account: format!("{}#{}", *HETZNER_S3_ENDPOINT, *HETZNER_S3_ACCESS_KEY),
+            name: self.bucket.clone(),
+        }
+    }
+
+    async fn put(&self, key: &str, body: ByteStream, opts: PutOptions) -> Result<(), StoreError> {
+        match opts.content_length {
+            Some(len) if len < self.multipart.threshold => self.put_single(key, body, opts).await,
+            _ => self.put_multipart(key, body, opts).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StoreError> {
         let resp = self
             .client
             .get_object()
@@ -113,59 +436,128 @@ impl S3Client {
             .key(key)
             .send()
             .await
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| match e.as_service_error() {
+                Some(service) if service.is_no_such_key() => StoreError::NotFound(key.to_string()),
+                _ => s3_error(e),
+            })?;
+
+        let stream = futures_util::stream::unfold(resp.body, |mut body| async move {
+            body.next()
+                .await
+                .map(|chunk| (chunk.map_err(std::io::Error::other), body))
+        });
+        Ok(Box::pin(stream))
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        let resp = match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+            Err(e) => return Err(s3_error(e)),
+        };
 
-        let data = resp.body.collect().await.map_err(|e| e.to_string())?;
-        Ok(data.into_bytes().to_vec())
+        Ok(Some(ObjectInfo {
+            key: key.to_string(),
+            size: resp.content_length.and_then(|len| len.try_into().ok()),
+            content_type: resp.content_type,
+            metadata: resp.metadata.unwrap_or_default().into_iter().collect(),
+        }))
     }
 
-    pub async fn delete_video(&self, key: &str) -> Result<(), aws_sdk_s3::Error> {
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
         self.client
             .delete_object()
             .bucket(&self.bucket)
             .key(key)
             .send()
-            .await?;
+            .await
+            .map_err(s3_error)?;
         Ok(())
     }
 
-    pub async fn upload_thumbnail(
+    async fn delete_many(&self, keys: &[String]) -> Result<Vec<(String, StoreError)>, StoreError> {
+        let mut failed = vec![];
+        for batch in keys.chunks(DELETE_BATCH_SIZE) {
+            let objects = batch
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| StoreError::S3(e.to_string()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                // Only report the objects that failed
+                .quiet(true)
+                .build()
+                .map_err(|e| StoreError::S3(e.to_string()))?;
+
+            let resp = self
+                .client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(delete)
+                .send()
+                .await
+                .map_err(s3_error)?;
+            failed.extend(resp.errors.unwrap_or_default().into_iter().map(|err| {
+                let message = format!(
+                    "deleting {} failed: {}",
+                    err.key().unwrap_or_default(),
+                    err.message().or(err.code()).unwrap_or("unknown error")
+                );
+                (err.key.unwrap_or_default(), StoreError::S3(message))
+            }));
+        }
+        Ok(failed)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError> {
+        self.list_objects(prefix, None).await
+    }
+
+    async fn list_after(
         &self,
-        key: &str,
-        data: Vec<u8>,
-    ) -> Result<(), aws_sdk_s3::Error> {
+        prefix: &str,
+        start_after: &str,
+    ) -> Result<Vec<ObjectInfo>, StoreError> {
+        self.list_objects(prefix, Some(start_after)).await
+    }
+
+    async fn copy(&self, src: &Bucket, key: &str) -> Result<(), StoreError> {
         self.client
-            .put_object()
+            .copy_object()
             .bucket(&self.bucket)
+            .copy_source(format!("{}/{key}", src.name))
             .key(key)
-            .body(ByteStream::from(data))
-            .content_type("image/png")
+            .metadata_directive(MetadataDirective::Copy)
             .send()
-            .await?;
+            .await
+            .map_err(s3_error)?;
         Ok(())
     }
 
-    pub async fn download_thumbnail(&self, key: &str) -> Result<Vec<u8>, String> {
-        let resp = self
+    async fn set_metadata(&self, key: &str, opts: PutOptions) -> Result<(), StoreError> {
+        // Copying an object onto itself is the only way to change S3 metadata
+        let mut request = self
             .client
-            .get_object()
+            .copy_object()
             .bucket(&self.bucket)
+            .copy_source(format!("{}/{key}", self.bucket))
             .key(key)
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
+            .metadata_directive(MetadataDirective::Replace)
+            .set_content_type(opts.content_type);
 
-        let data = resp.body.collect().await.map_err(|e| e.to_string())?;
-        Ok(data.into_bytes().to_vec())
-    }
+        for (k, v) in opts.metadata {
+            request = request.metadata(k, v);
+        }
 
-    pub async fn delete_thumbnail(&self, key: &str) -> Result<(), aws_sdk_s3::Error> {
-        self.client
-            .delete_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await?;
+        request.send().await.map_err(s3_error)?;
         Ok(())
     }
 }
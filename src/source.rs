@@ -0,0 +1,272 @@
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header::AUTHORIZATION, redirect, StatusCode, Url};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use storj_interface::duplicate::Source;
+use storj_interface::hosts::{is_internal_host, is_internal_ip};
+
+use crate::consts::{ALLOW_HTTP_SOURCES, CLOUDFLARE_STREAM_API_TOKEN, CLOUDFLARE_STREAM_BASE_URL};
+use crate::store::{self, ByteStream, Storage, StoreError};
+
+/// Redirects followed before giving up on a url source
+const MAX_REDIRECTS: usize = 10;
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// Client for url sources, which are chosen by callers. Every redirect is
+/// checked like the url itself, and names resolving to internal addresses
+/// are refused, so a url can't make us fetch from our own network.
+static URL_HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error(Refused("too many redirects".into()));
+            }
+            match check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(refused) => attempt.error(refused),
+            }
+        }))
+        .build()
+        .expect("url source client to build")
+});
+
+/// Why a url source, or a url it redirected to, can't be fetched
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct Refused(String);
+
+/// Check the scheme and host of a url source before sending anything to it
+fn check_url(url: &Url) -> Result<(), Refused> {
+    match url.scheme() {
+        "https" => {}
+        "http" if *ALLOW_HTTP_SOURCES => {}
+        scheme => {
+            return Err(Refused(format!(
+                "{scheme} urls are not allowed, use https: {url}"
+            )))
+        }
+    }
+    if *ALLOW_HTTP_SOURCES {
+        return Ok(());
+    }
+
+    match url.host_str() {
+        None => Err(Refused(format!("{url} has no host"))),
+        Some(host) if is_internal_host(host) => {
+            Err(Refused(format!("internal hosts are not allowed: {url}")))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Resolves host names like the default resolver, but refuses names with an
+/// internal address
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if !*ALLOW_HTTP_SOURCES {
+                if let Some(addr) = addrs.iter().find(|addr| is_internal_ip(addr.ip())) {
+                    return Err(Refused(format!(
+                        "{host} resolves to the internal address {}",
+                        addr.ip()
+                    ))
+                    .into());
+                }
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SourceError {
+    #[error("the video doesn't exist on {provider}")]
+    NotFound { provider: &'static str },
+
+    #[error("{provider} rejected our credentials ({status})")]
+    Unauthorized {
+        provider: &'static str,
+        status: StatusCode,
+    },
+
+    #[error("{provider} returned non-ok status ({status}) when fetching the video")]
+    Upstream {
+        provider: &'static str,
+        status: StatusCode,
+    },
+
+    #[error("couldn't reach {provider}: {source}")]
+    Network {
+        provider: &'static str,
+        source: reqwest::Error,
+    },
+
+    #[error("invalid source: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+/// An opened video, ready to be streamed
+pub struct Fetched {
+    pub stream: ByteStream,
+    /// Size of the video if the source announced it
+    pub content_length: Option<u64>,
+}
+
+/// Somewhere a video can be fetched from
+#[async_trait]
+pub trait VideoSource: Send + Sync {
+    /// Short name of the provider, used in logs and errors
+    fn name(&self) -> &'static str;
+
+    async fn open(&self) -> Result<Fetched, SourceError>;
+}
+
+/// The source described by a duplication request
+pub fn resolve(source: &Source, storage: &Storage, video_id: &str) -> Box<dyn VideoSource> {
+    match source {
+        Source::CloudflareStream => Box::new(CloudflareStream {
+            video_id: video_id.to_string(),
+        }),
+        Source::Url { url, authorization } => Box::new(HttpUrl {
+            url: url.clone(),
+            authorization: authorization.clone(),
+        }),
+        Source::Object {
+            publisher_user_id,
+            video_id,
+            is_nsfw,
+        } => Box::new(StoredObject {
+            storage: storage.clone(),
+            key: store::video_key(publisher_user_id, video_id),
+            is_nsfw: *is_nsfw,
+        }),
+    }
+}
+
+/// Send a GET request, mapping the response status to a [`SourceError`]
+async fn fetch(
+    provider: &'static str,
+    request: reqwest::RequestBuilder,
+) -> Result<Fetched, SourceError> {
+    let resp = request.send().await.map_err(|source| {
+        // A url refused midway, by a redirect or its resolved address, is as
+        // unusable as one refused upfront
+        let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(&source);
+        while let Some(err) = cause {
+            if let Some(refused) = err.downcast_ref::<Refused>() {
+                return SourceError::Invalid(refused.to_string());
+            }
+            cause = err.source();
+        }
+        SourceError::Network { provider, source }
+    })?;
+
+    match resp.status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_FOUND => return Err(SourceError::NotFound { provider }),
+        status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+            return Err(SourceError::Unauthorized { provider, status })
+        }
+        status => return Err(SourceError::Upstream { provider, status }),
+    }
+
+    Ok(Fetched {
+        content_length: resp.content_length(),
+        stream: Box::pin(resp.bytes_stream().map_err(std::io::Error::other)),
+    })
+}
+
+/// The default mp4 download of a video on cloudflare stream
+pub struct CloudflareStream {
+    video_id: String,
+}
+
+#[async_trait]
+impl VideoSource for CloudflareStream {
+    fn name(&self) -> &'static str {
+        "cloudflare"
+    }
+
+    async fn open(&self) -> Result<Fetched, SourceError> {
+        let url = format!(
+            "{}/{}/downloads/default.mp4",
+            CLOUDFLARE_STREAM_BASE_URL.as_str(),
+            self.video_id
+        );
+
+        let mut request = HTTP.get(url);
+        if let Some(token) = CLOUDFLARE_STREAM_API_TOKEN.as_deref() {
+            request = request.bearer_auth(token);
+        }
+
+        fetch(self.name(), request).await
+    }
+}
+
+/// Any url serving the mp4
+pub struct HttpUrl {
+    url: String,
+    authorization: Option<String>,
+}
+
+#[async_trait]
+impl VideoSource for HttpUrl {
+    fn name(&self) -> &'static str {
+        "url"
+    }
+
+    async fn open(&self) -> Result<Fetched, SourceError> {
+        let url = Url::parse(&self.url)
+            .map_err(|e| SourceError::Invalid(format!("{}: {e}", self.url)))?;
+        check_url(&url).map_err(|refused| SourceError::Invalid(refused.to_string()))?;
+
+        let mut request = URL_HTTP.get(url);
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        fetch(self.name(), request).await
+    }
+}
+
+/// A video already stored in one of our tiers
+pub struct StoredObject {
+    storage: Storage,
+    key: String,
+    is_nsfw: bool,
+}
+
+#[async_trait]
+impl VideoSource for StoredObject {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    async fn open(&self) -> Result<Fetched, SourceError> {
+        let tier = self.storage.tier(self.is_nsfw);
+        let Some(store) = tier.locate(&self.key).await? else {
+            return Err(SourceError::NotFound {
+                provider: self.name(),
+            });
+        };
+        let content_length = store.head(&self.key).await?.and_then(|info| info.size);
+
+        Ok(Fetched {
+            stream: store.get(&self.key).await?,
+            content_length,
+        })
+    }
+}
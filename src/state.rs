@@ -0,0 +1,23 @@
+use axum::extract::FromRef;
+
+use crate::jobs::JobQueue;
+use crate::store::Storage;
+
+/// Everything the handlers share
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Storage,
+    pub jobs: JobQueue,
+}
+
+impl FromRef<AppState> for Storage {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for JobQueue {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
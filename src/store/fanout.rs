@@ -0,0 +1,54 @@
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::ByteStream;
+
+/// Number of chunks each branch may buffer before the source is paused
+const BRANCH_CAPACITY: usize = 16;
+
+/// Split a stream into `n` branches that each see every chunk.
+///
+/// Chunks are forwarded at the pace of the slowest branch, so at most
+/// [`BRANCH_CAPACITY`] chunks per branch are held in memory. A branch that is
+/// dropped early (e.g. ffmpeg stopped reading after grabbing a frame) is simply
+/// skipped from then on. An error from the source is forwarded to every branch.
+pub fn fanout(mut source: ByteStream, n: usize) -> Vec<ByteStream> {
+    let (senders, branches): (Vec<_>, Vec<_>) = (0..n)
+        .map(|_| {
+            let (tx, rx) = mpsc::channel(BRANCH_CAPACITY);
+            let branch: ByteStream = Box::pin(ReceiverStream::new(rx));
+            (tx, branch)
+        })
+        .unzip();
+
+    tokio::spawn(async move {
+        let mut senders = senders;
+        while let Some(chunk) = source.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    let mut live = Vec::with_capacity(senders.len());
+                    for tx in senders {
+                        if tx.send(Ok(chunk.clone())).await.is_ok() {
+                            live.push(tx);
+                        }
+                    }
+                    senders = live;
+                    if senders.is_empty() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    for tx in &senders {
+                        tx.send(Err(std::io::Error::new(err.kind(), err.to_string())))
+                            .await
+                            .ok();
+                    }
+                    return;
+                }
+            }
+        }
+    });
+
+    branches
+}
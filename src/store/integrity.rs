@@ -0,0 +1,136 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::oneshot;
+
+use super::{ByteStream, ObjectInfo};
+
+/// Metadata key holding the hex encoded SHA-256 of an object
+pub const SHA256_METADATA_KEY: &str = "_sha256";
+/// Metadata key holding the size of an object in bytes
+pub const SIZE_METADATA_KEY: &str = "_size";
+
+/// Fingerprint of an object's content
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Checksum {
+    /// Hex encoded SHA-256
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Checksum {
+    /// The checksum stored in an object's metadata, if it carries one
+    pub fn stored(info: &ObjectInfo) -> Option<Self> {
+        Some(Self {
+            sha256: info.metadata.get(SHA256_METADATA_KEY)?.clone(),
+            size: info.metadata.get(SIZE_METADATA_KEY)?.parse().ok()?,
+        })
+    }
+
+    /// Check a stored object against the checksum, using whatever the backend reports
+    pub fn verify(&self, info: &ObjectInfo) -> Result<(), String> {
+        if let Some(size) = info.size.filter(|size| *size != self.size) {
+            return Err(format!(
+                "{} has {size} bytes, expected {}",
+                info.key, self.size
+            ));
+        }
+        if let Some(sha256) = info
+            .metadata
+            .get(SHA256_METADATA_KEY)
+            .filter(|sha256| **sha256 != self.sha256)
+        {
+            return Err(format!(
+                "{} has sha256 {sha256}, expected {}",
+                info.key, self.sha256
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add the checksum to an object's metadata
+    pub fn apply(&self, metadata: &mut BTreeMap<String, String>) {
+        metadata.insert(SHA256_METADATA_KEY.into(), self.sha256.clone());
+        metadata.insert(SIZE_METADATA_KEY.into(), self.size.to_string());
+    }
+}
+
+/// Stream wrapper hashing every chunk that passes through
+struct Checksummed {
+    inner: ByteStream,
+    hasher: Sha256,
+    size: u64,
+    expected_size: Option<u64>,
+    done: Option<oneshot::Sender<Checksum>>,
+}
+
+impl Stream for Checksummed {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(done) = this.done.take() else {
+            return Poll::Ready(None);
+        };
+
+        match this.inner.as_mut().poll_next(cx) {
+            Poll::Pending => {
+                this.done = Some(done);
+                Poll::Pending
+            }
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.hasher.update(&chunk);
+                this.size += chunk.len() as u64;
+                this.done = Some(done);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            // Dropping `done` without a value tells the receiver the stream failed
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err))),
+            Poll::Ready(None) => match this.expected_size {
+                Some(expected) if expected != this.size => {
+                    Poll::Ready(Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "stream ended after {} bytes, expected {expected}",
+                            this.size
+                        ),
+                    ))))
+                }
+                _ => {
+                    let hasher = std::mem::take(&mut this.hasher);
+                    done.send(Checksum {
+                        sha256: hex::encode(hasher.finalize()),
+                        size: this.size,
+                    })
+                    .ok();
+                    Poll::Ready(None)
+                }
+            },
+        }
+    }
+}
+
+/// Hash a stream while it is being consumed.
+///
+/// If `expected_size` is given and the stream ends at a different size, the
+/// stream yields an error as its final item, so consumers abort instead of
+/// storing a truncated object. The receiver resolves once the stream has been
+/// fully consumed and errors if it failed.
+pub fn checksummed(
+    stream: ByteStream,
+    expected_size: Option<u64>,
+) -> (ByteStream, oneshot::Receiver<Checksum>) {
+    let (tx, rx) = oneshot::channel();
+    let stream = Checksummed {
+        inner: stream,
+        hasher: Sha256::new(),
+        size: 0,
+        expected_size,
+        done: Some(tx),
+    };
+    (Box::pin(stream), rx)
+}
@@ -0,0 +1,670 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::s3_client::S3Client;
+
+pub mod fanout;
+pub mod integrity;
+pub mod outcome;
+pub mod progress;
+pub mod retry;
+pub mod uplink;
+
+pub use fanout::fanout;
+pub use integrity::{checksummed, Checksum};
+pub use outcome::{Consistency, Outcomes};
+pub use progress::Progress;
+pub use retry::{RetryPolicy, Retrying};
+pub use uplink::{UplinkError, UplinkFailure, UplinkStore};
+
+/// A stream of object bytes flowing into or out of a store
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// Wrap an in-memory buffer as a [`ByteStream`]
+pub fn once(data: Bytes) -> ByteStream {
+    Box::pin(futures_util::stream::once(async move { Ok(data) }))
+}
+
+/// Drain a [`ByteStream`] into memory
+pub async fn collect(stream: ByteStream) -> std::io::Result<Bytes> {
+    let chunks: Vec<Bytes> = stream.try_collect().await?;
+    Ok(chunks.concat().into())
+}
+
+/// Object key of the mp4 for a video
+pub fn video_key(publisher_user_id: &str, video_id: &str) -> String {
+    format!("{publisher_user_id}/{video_id}.mp4")
+}
+
+/// Object key of the thumbnail extracted from a video
+pub fn thumbnail_key(publisher_user_id: &str, video_id: &str) -> String {
+    format!("{publisher_user_id}/{video_id}_thumbnail.png")
+}
+
+/// Object key of a file (playlist or segment) belonging to a video's HLS tree
+pub fn hls_key(video_id: &str, hls_file_name: &str) -> String {
+    format!("{video_id}/hls/{hls_file_name}")
+}
+
+/// Prefix of the copies stores stage while rewriting an object. Identifiers
+/// can't start with a dot, so it never overlaps a publisher's or video's keys.
+pub const STAGING_PREFIX: &str = ".staging/";
+
+/// Staged copies older than this were left behind by a rewrite that never finished
+const STAGING_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Key to stage a rewritten copy of an object under, named after when it was staged
+pub fn staging_key() -> String {
+    format!(
+        "{STAGING_PREFIX}{}-{}",
+        chrono::Utc::now().timestamp(),
+        uuid::Uuid::new_v4()
+    )
+}
+
+/// Id of the video an mp4 or thumbnail key of the publisher belongs to
+pub fn video_id_of<'a>(publisher_user_id: &str, key: &'a str) -> Option<&'a str> {
+    let name = key.strip_prefix(publisher_user_id)?.strip_prefix('/')?;
+    let video_id = name
+        .strip_suffix(".mp4")
+        .or_else(|| name.strip_suffix("_thumbnail.png"))?;
+    (!video_id.is_empty() && !video_id.contains('/')).then_some(video_id)
+}
+
+/// Guess the content type of an object from its key
+pub fn content_type_for(key: &str) -> &'static str {
+    if key.ends_with(".mp4") {
+        "video/mp4"
+    } else if key.ends_with(".png") {
+        "image/png"
+    } else if key.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else if key.ends_with(".ts") {
+        "video/mp2t"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    /// key-value pairs stored alongside the object
+    pub metadata: BTreeMap<String, String>,
+    /// Expire the object after this long. Backends without object expiry ignore this.
+    pub ttl: Option<Duration>,
+    /// Size of the body, if known up front. Lets backends stream instead of buffering.
+    pub content_length: Option<u64>,
+}
+
+impl PutOptions {
+    pub fn for_key(key: &str) -> Self {
+        Self {
+            content_type: Some(content_type_for(key).into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_content_length(mut self, content_length: Option<u64>) -> Self {
+        self.content_length = content_length;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("object {0} doesn't exist")]
+    NotFound(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Uplink(#[from] UplinkError),
+
+    #[error("S3 operation failed: {0}")]
+    S3(String),
+
+    #[error("S3 operation timed out: {0}")]
+    S3Timeout(String),
+
+    #[error("integrity check failed: {0}")]
+    Integrity(String),
+
+    #[error("uploading to {backend} failed: {source}")]
+    Upload {
+        backend: &'static str,
+        source: Box<StoreError>,
+    },
+}
+
+impl StoreError {
+    /// Name of the backend that failed, if known
+    pub fn backend(&self) -> Option<&'static str> {
+        match self {
+            StoreError::Upload { backend, .. } => Some(backend),
+            StoreError::Uplink(err) => Some(err.backend),
+            _ => None,
+        }
+    }
+
+    /// Whether the same operation may succeed when tried again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StoreError::NotFound(_) => false,
+            StoreError::Upload { source, .. } => source.is_retryable(),
+            StoreError::Uplink(err) => err.failure.is_retryable(),
+            _ => true,
+        }
+    }
+}
+
+/// A bucket along with the account it is accessed with
+#[derive(Clone, PartialEq, Eq)]
+pub struct Bucket {
+    /// Identifies the credentials of the account. Stores of the same account
+    /// can copy objects between their buckets server side.
+    pub account: String,
+    pub name: String,
+}
+
+/// A place where objects live, e.g. a storj bucket accessed via uplink or an S3 bucket
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Short name of the backend, used in logs and responses
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend honors [`PutOptions::ttl`]
+    fn expires_objects(&self) -> bool;
+
+    /// The bucket the store keeps its objects in
+    fn bucket(&self) -> Bucket;
+
+    async fn put(&self, key: &str, body: ByteStream, opts: PutOptions) -> Result<(), StoreError>;
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StoreError>;
+
+    /// Returns `None` if the object doesn't exist
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError>;
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+
+    /// Delete several objects, in as few requests as the backend allows.
+    /// Objects that don't exist count as deleted.
+    ///
+    /// Returns the keys that couldn't be deleted along with why. Fails as a
+    /// whole only if the backend couldn't be asked at all.
+    async fn delete_many(&self, keys: &[String]) -> Result<Vec<(String, StoreError)>, StoreError> {
+        let mut failed = vec![];
+        for key in keys {
+            match self.delete(key).await {
+                Ok(()) | Err(StoreError::NotFound(_)) => {}
+                Err(err) => failed.push((key.clone(), err)),
+            }
+        }
+        Ok(failed)
+    }
+
+    /// List every object whose key starts with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError>;
+
+    /// List every object whose key starts with `prefix` and sorts after
+    /// `start_after`. Defaults to filtering a full listing.
+    async fn list_after(
+        &self,
+        prefix: &str,
+        start_after: &str,
+    ) -> Result<Vec<ObjectInfo>, StoreError> {
+        let mut objects = self.list(prefix).await?;
+        objects.retain(|info| info.key.as_str() > start_after);
+        Ok(objects)
+    }
+
+    /// Copy an object from `src`, a bucket of the same account, server side
+    /// and keeping its metadata
+    async fn copy(&self, src: &Bucket, key: &str) -> Result<(), StoreError>;
+
+    /// Replace the metadata, content type and expiry of an existing object.
+    /// Stores that can't do so in place rewrite the object through a copy
+    /// staged under [`STAGING_PREFIX`].
+    async fn set_metadata(&self, key: &str, opts: PutOptions) -> Result<(), StoreError>;
+
+    /// Record the checksum of an object that was only known once the object
+    /// was written with `opts`. Defaults to adding it to the object's metadata.
+    async fn attach_checksum(
+        &self,
+        key: &str,
+        checksum: &Checksum,
+        mut opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        checksum.apply(&mut opts.metadata);
+        self.set_metadata(key, opts).await
+    }
+}
+
+/// A group of stores that hold the same content, e.g. everything that is safe for work
+#[derive(Clone)]
+pub struct Tier {
+    stores: Vec<Arc<dyn ObjectStore>>,
+    progress: Option<Progress>,
+    retry: RetryPolicy,
+    /// Set in tolerant mode, see [`Tier::tolerant`]
+    outcomes: Option<Outcomes>,
+}
+
+impl Tier {
+    /// `stores` are given in preferred read order. Every operation on them is
+    /// retried according to `retry`, except for streamed uploads.
+    pub fn new(stores: Vec<Arc<dyn ObjectStore>>, retry: RetryPolicy) -> Self {
+        let stores = stores
+            .into_iter()
+            .map(|store| Arc::new(Retrying::new(store, retry)) as Arc<dyn ObjectStore>)
+            .collect();
+        Self {
+            stores,
+            progress: None,
+            retry,
+            outcomes: None,
+        }
+    }
+
+    /// The same tier, reporting uploads to `progress`
+    pub fn tracked(&self, progress: &Progress) -> Self {
+        Self {
+            progress: Some(progress.clone()),
+            ..self.clone()
+        }
+    }
+
+    /// The same tier, tolerating failures of some of its stores.
+    ///
+    /// Writes succeed as long as one store took them, failures are recorded in
+    /// `outcomes`. Once a write of a key failed on a store, that store is left
+    /// out of further writes and checks of the key.
+    pub fn tolerant(&self, outcomes: &Outcomes) -> Self {
+        Self {
+            outcomes: Some(outcomes.clone()),
+            ..self.clone()
+        }
+    }
+
+    /// The same tier, limited to the stores named in `names`
+    pub fn only(&self, names: &[&str]) -> Self {
+        Self {
+            stores: self
+                .stores
+                .iter()
+                .filter(|store| names.contains(&store.name()))
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Stores a write of `key` goes to
+    fn targets(&self, key: &str) -> Vec<&Arc<dyn ObjectStore>> {
+        self.stores
+            .iter()
+            .filter(|store| {
+                self.outcomes
+                    .as_ref()
+                    .is_none_or(|outcomes| !outcomes.has_failed(key, store.name()))
+            })
+            .collect()
+    }
+
+    /// Combine the results of an operation on each of `stores`. Any failure
+    /// fails the whole operation, unless the tier is tolerant and some store
+    /// succeeded.
+    fn settle(
+        &self,
+        key: &str,
+        stores: &[&Arc<dyn ObjectStore>],
+        results: Vec<Result<(), StoreError>>,
+    ) -> Result<(), StoreError> {
+        let Some(outcomes) = &self.outcomes else {
+            return results.into_iter().collect();
+        };
+
+        for (store, result) in stores.iter().zip(&results) {
+            outcomes.record(key, store.name(), result);
+        }
+        if results.iter().any(Result::is_ok) {
+            return Ok(());
+        }
+        results.into_iter().collect()
+    }
+
+    async fn put_tracked(
+        &self,
+        store: &Arc<dyn ObjectStore>,
+        key: &str,
+        mut body: ByteStream,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        if let Some(progress) = &self.progress {
+            body = progress.track(store.name(), body);
+        }
+        let result = store.put(key, body, opts).await;
+        if let Some(progress) = &self.progress {
+            progress.finish(store.name(), &result);
+        }
+        result.map_err(|source| StoreError::Upload {
+            backend: store.name(),
+            source: Box::new(source),
+        })
+    }
+
+    /// Write the same buffer to every store of the tier concurrently,
+    /// retrying each store on its own
+    pub async fn put_bytes(
+        &self,
+        key: &str,
+        data: Bytes,
+        opts: &PutOptions,
+    ) -> Result<(), StoreError> {
+        let opts = opts.clone().with_content_length(Some(data.len() as u64));
+        let stores = self.targets(key);
+        let results = futures_util::future::join_all(stores.iter().map(|store| {
+            self.retry
+                .run(format!("upload of {key} to {}", store.name()), || {
+                    self.put_tracked(store, key, once(data.clone()), opts.clone())
+                })
+        }))
+        .await;
+        self.settle(key, &stores, results)
+    }
+
+    /// Stream the same body to every store of the tier concurrently
+    pub async fn put_stream(
+        &self,
+        key: &str,
+        body: ByteStream,
+        opts: &PutOptions,
+    ) -> Result<(), StoreError> {
+        let stores = self.targets(key);
+        let branches = fanout(body, stores.len());
+        // A store failing drops its branch, the others keep streaming
+        let results = futures_util::future::join_all(
+            stores
+                .iter()
+                .zip(branches)
+                .map(|(store, branch)| self.put_tracked(store, key, branch, opts.clone())),
+        )
+        .await;
+        self.settle(key, &stores, results)
+    }
+
+    /// Replace the metadata of an object in every store of the tier
+    pub async fn set_metadata(&self, key: &str, opts: &PutOptions) -> Result<(), StoreError> {
+        let stores = self.targets(key);
+        let results = futures_util::future::join_all(
+            stores
+                .iter()
+                .map(|store| store.set_metadata(key, opts.clone())),
+        )
+        .await;
+        self.settle(key, &stores, results)
+    }
+
+    /// Record the checksum of an object written with `opts` in every store of the tier
+    pub async fn attach_checksum(
+        &self,
+        key: &str,
+        checksum: &Checksum,
+        opts: &PutOptions,
+    ) -> Result<(), StoreError> {
+        let stores = self.targets(key);
+        let results = futures_util::future::join_all(
+            stores
+                .iter()
+                .map(|store| store.attach_checksum(key, checksum, opts.clone())),
+        )
+        .await;
+        self.settle(key, &stores, results)
+    }
+
+    /// Check that every store of the tier holds a complete copy of the object
+    pub async fn verify(&self, key: &str, checksum: &Checksum) -> Result<(), StoreError> {
+        let stores = self.targets(key);
+        let mut results = Vec::with_capacity(stores.len());
+        for store in &stores {
+            results.push(verify_copy(store, key, checksum).await);
+        }
+        self.settle(key, &stores, results)
+    }
+
+    /// Copy an object server side from `source` to the stores of the tier in
+    /// the same account, instead of streaming it through the service.
+    ///
+    /// Returns the names of the stores that took the copy, the others are
+    /// left to be written to.
+    pub async fn copy_from(&self, source: &Arc<dyn ObjectStore>, key: &str) -> Vec<&'static str> {
+        let src = source.bucket();
+        let stores = self
+            .targets(key)
+            .into_iter()
+            .filter(|store| store.bucket().account == src.account);
+
+        let mut copied = vec![];
+        for store in stores {
+            match store.copy(&src, key).await {
+                Ok(()) => copied.push(store.name()),
+                Err(e) => eprintln!(
+                    "Copying {key} from {} to {} server side failed: {e}",
+                    source.name(),
+                    store.name()
+                ),
+            }
+        }
+        copied
+    }
+
+    /// Names of the stores of the tier, in read order
+    pub fn backends(&self) -> Vec<&'static str> {
+        self.stores.iter().map(|store| store.name()).collect()
+    }
+
+    /// Whether the store named `backend` honors [`PutOptions::ttl`]
+    pub fn expires_objects(&self, backend: &str) -> bool {
+        self.stores
+            .iter()
+            .any(|store| store.name() == backend && store.expires_objects())
+    }
+
+    /// Objects starting with `prefix`, as listed by each store of the tier
+    pub async fn list_each(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(&'static str, Vec<ObjectInfo>)>, StoreError> {
+        let mut listings = Vec::with_capacity(self.stores.len());
+        for store in &self.stores {
+            listings.push((store.name(), store.list(prefix).await?));
+        }
+        Ok(listings)
+    }
+
+    /// Objects starting with `prefix` and sorting after `start_after`, as
+    /// listed by each store of the tier
+    pub async fn list_each_after(
+        &self,
+        prefix: &str,
+        start_after: &str,
+    ) -> Result<Vec<(&'static str, Vec<ObjectInfo>)>, StoreError> {
+        let mut listings = Vec::with_capacity(self.stores.len());
+        for store in &self.stores {
+            listings.push((store.name(), store.list_after(prefix, start_after).await?));
+        }
+        Ok(listings)
+    }
+
+    /// Keys starting with `prefix` held by any store of the tier
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        let mut keys = BTreeSet::new();
+        for (_, objects) in self.list_each(prefix).await? {
+            keys.extend(objects.into_iter().map(|info| info.key));
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Delete an object from every store of the tier. Stores that don't have it are skipped.
+    pub async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let results = futures_util::future::join_all(self.stores.iter().map(|store| async move {
+            let result = match store.delete(key).await {
+                Err(StoreError::NotFound(_)) => Ok(()),
+                result => result,
+            };
+            if let Some(progress) = &self.progress {
+                progress.finish(store.name(), &result);
+            }
+            result
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Delete objects from every store of the tier, batching the deletions
+    /// where the store supports it. Stores that don't have an object are skipped.
+    pub async fn delete_many(&self, keys: &[String]) -> Result<(), StoreError> {
+        let results = futures_util::future::join_all(self.stores.iter().map(|store| async move {
+            let failed = store.delete_many(keys).await;
+            if let Some(progress) = &self.progress {
+                match &failed {
+                    Ok(failed) => progress.finish_batch(store.name(), keys.len(), failed),
+                    Err(_) => progress.finish(store.name(), &failed),
+                }
+            }
+            match failed?.into_iter().next() {
+                Some((_, err)) => Err(err),
+                None => Ok(()),
+            }
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Look up an object in every store of the tier
+    pub async fn head_all(
+        &self,
+        key: &str,
+    ) -> Vec<(&'static str, Result<Option<ObjectInfo>, StoreError>)> {
+        futures_util::future::join_all(
+            self.stores
+                .iter()
+                .map(|store| async move { (store.name(), store.head(key).await) }),
+        )
+        .await
+    }
+
+    /// Find the first store (in read order) that has the object
+    pub async fn locate(&self, key: &str) -> Result<Option<Arc<dyn ObjectStore>>, StoreError> {
+        for store in &self.stores {
+            if store.head(key).await?.is_some() {
+                return Ok(Some(store.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Check that a store holds a complete copy of the object
+async fn verify_copy(
+    store: &Arc<dyn ObjectStore>,
+    key: &str,
+    checksum: &Checksum,
+) -> Result<(), StoreError> {
+    let info = store
+        .head(key)
+        .await?
+        .ok_or_else(|| StoreError::NotFound(key.to_string()))?;
+    checksum
+        .verify(&info)
+        .map_err(|e| StoreError::Integrity(format!("{} copy of {e}", store.name())))
+}
+
+/// Every tier the service writes to
+#[derive(Clone)]
+pub struct Storage {
+    pub sfw: Tier,
+    pub nsfw: Tier,
+}
+
+impl Storage {
+    pub fn new(s3_client: S3Client) -> Self {
+        let retry = RetryPolicy::from_env();
+        let sfw = Tier::new(
+            vec![Arc::new(s3_client), Arc::new(UplinkStore::sfw())],
+            retry,
+        );
+        let nsfw = Tier::new(vec![Arc::new(UplinkStore::nsfw())], retry);
+
+        Self { sfw, nsfw }
+    }
+
+    pub fn tier(&self, is_nsfw: bool) -> &Tier {
+        if is_nsfw {
+            &self.nsfw
+        } else {
+            &self.sfw
+        }
+    }
+
+    /// Delete the copies staged by rewrites that never finished, e.g. as the
+    /// service stopped midway
+    pub async fn clear_staging(&self) {
+        let cutoff = chrono::Utc::now().timestamp() - STAGING_MAX_AGE.as_secs() as i64;
+        for tier in [&self.sfw, &self.nsfw] {
+            for backend in tier.backends() {
+                let store = tier.only(&[backend]);
+                let keys = match store.list(STAGING_PREFIX).await {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        eprintln!("Couldn't list staged copies on {backend}: {e}");
+                        continue;
+                    }
+                };
+                for key in keys {
+                    let staged_at = key
+                        .strip_prefix(STAGING_PREFIX)
+                        .and_then(|name| name.split_once('-'))
+                        .and_then(|(timestamp, _)| timestamp.parse::<i64>().ok());
+                    if staged_at.is_some_and(|staged_at| staged_at >= cutoff) {
+                        continue;
+                    }
+                    match store.delete(&key).await {
+                        Ok(()) => println!("Deleted staged copy {key} from {backend}"),
+                        Err(e) => {
+                            eprintln!("Couldn't delete staged copy {key} from {backend}: {e}")
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use super::StoreError;
+
+/// Whether a backend holds everything written to the tier
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendState {
+    Consistent,
+    /// Some writes failed, a repair job brings the backend up to date
+    RepairPending,
+}
+
+/// Which backends every object could be written to, collected by a tier in
+/// [`super::Tier::tolerant`] mode
+#[derive(Clone, Default)]
+pub struct Outcomes {
+    inner: Arc<Mutex<Recorded>>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    backends: BTreeSet<&'static str>,
+    /// Key to the backends that failed it, with the error
+    failed: BTreeMap<String, BTreeMap<&'static str, String>>,
+    /// Key to the backends that took every write of it
+    took: BTreeMap<String, BTreeSet<&'static str>>,
+}
+
+impl Outcomes {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Recorded> {
+        self.inner.lock().expect("outcomes lock to not be poisoned")
+    }
+
+    pub(super) fn record(&self, key: &str, backend: &'static str, result: &Result<(), StoreError>) {
+        let mut recorded = self.lock();
+        recorded.backends.insert(backend);
+        match result {
+            Ok(()) => {
+                let failed_before = recorded
+                    .failed
+                    .get(key)
+                    .is_some_and(|backends| backends.contains_key(backend));
+                if !failed_before {
+                    recorded
+                        .took
+                        .entry(key.to_string())
+                        .or_default()
+                        .insert(backend);
+                }
+            }
+            Err(err) => {
+                if let Some(took) = recorded.took.get_mut(key) {
+                    took.remove(backend);
+                }
+                recorded
+                    .failed
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(backend, err.to_string());
+            }
+        }
+    }
+
+    /// Whether a write of `key` to `backend` failed before
+    pub(super) fn has_failed(&self, key: &str, backend: &str) -> bool {
+        self.lock()
+            .failed
+            .get(key)
+            .is_some_and(|backends| backends.contains_key(backend))
+    }
+
+    /// Keys that are missing from, or broken on, at least one backend
+    pub fn divergent_keys(&self) -> Vec<String> {
+        self.lock().failed.keys().cloned().collect()
+    }
+
+    /// Divergent keys to the backends that took every write of them, i.e.
+    /// the ones holding the version to repair the others from
+    pub fn sources(&self) -> BTreeMap<String, Vec<String>> {
+        let recorded = self.lock();
+        recorded
+            .failed
+            .keys()
+            .map(|key| {
+                let took = recorded.took.get(key).into_iter().flatten();
+                (
+                    key.clone(),
+                    took.map(|backend| backend.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    /// Summary of the outcomes, `repair_job_id` being the job that repairs the
+    /// [`Outcomes::divergent_keys`]
+    pub fn consistency(&self, repair_job_id: Option<String>) -> Consistency {
+        let recorded = self.lock();
+        let backends = recorded
+            .backends
+            .iter()
+            .map(|&backend| {
+                let failed = recorded
+                    .failed
+                    .values()
+                    .any(|backends| backends.contains_key(backend));
+                let state = if failed {
+                    BackendState::RepairPending
+                } else {
+                    BackendState::Consistent
+                };
+                (backend, state)
+            })
+            .collect();
+
+        Consistency {
+            consistent: recorded.failed.is_empty(),
+            backends,
+            repair_job_id,
+        }
+    }
+}
+
+/// How consistent the backends of a tier are after a write, as reported to callers
+#[derive(Serialize, Debug)]
+pub struct Consistency {
+    /// Every backend holds every object
+    pub consistent: bool,
+    pub backends: BTreeMap<&'static str, BackendState>,
+    /// Job copying the missing objects to the backends that lack them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair_job_id: Option<String>,
+}
@@ -0,0 +1,80 @@
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::ByteStream;
+
+/// How far along a single backend is
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BackendProgress {
+    /// Bytes written to the backend so far
+    pub bytes: u64,
+    /// Objects written or deleted successfully
+    pub completed: u32,
+    /// Objects that failed
+    pub failed: u32,
+    /// The last failure, if any
+    pub error: Option<String>,
+}
+
+/// Per-backend progress of an operation spanning several stores
+#[derive(Clone, Default)]
+pub struct Progress {
+    backends: Arc<Mutex<BTreeMap<String, BackendProgress>>>,
+}
+
+impl Progress {
+    fn update(&self, backend: &str, f: impl FnOnce(&mut BackendProgress)) {
+        let mut backends = self
+            .backends
+            .lock()
+            .expect("progress lock to not be poisoned");
+        f(backends.entry(backend.to_string()).or_default());
+    }
+
+    /// Count the bytes of a stream flowing into `backend`
+    pub fn track(&self, backend: &str, stream: ByteStream) -> ByteStream {
+        let progress = self.clone();
+        let backend = backend.to_string();
+        Box::pin(stream.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                progress.update(&backend, |p| p.bytes += chunk.len() as u64);
+            }
+        }))
+    }
+
+    /// Record the outcome of a single object operation on `backend`
+    pub fn finish<T, E: std::fmt::Display>(&self, backend: &str, result: &Result<T, E>) {
+        self.update(backend, |p| match result {
+            Ok(_) => p.completed += 1,
+            Err(e) => {
+                p.failed += 1;
+                p.error = Some(e.to_string());
+            }
+        });
+    }
+
+    /// Record the outcome of an operation on `total` objects on `backend`, of which `failed` failed
+    pub fn finish_batch<E: std::fmt::Display>(
+        &self,
+        backend: &str,
+        total: usize,
+        failed: &[(String, E)],
+    ) {
+        self.update(backend, |p| {
+            p.completed += total.saturating_sub(failed.len()) as u32;
+            p.failed += failed.len() as u32;
+            if let Some((key, e)) = failed.last() {
+                p.error = Some(format!("{key}: {e}"));
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, BackendProgress> {
+        self.backends
+            .lock()
+            .expect("progress lock to not be poisoned")
+            .clone()
+    }
+}
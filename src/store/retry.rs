@@ -0,0 +1,193 @@
+use async_trait::async_trait;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Bucket, ByteStream, Checksum, ObjectInfo, ObjectStore, PutOptions, StoreError};
+use crate::consts::{
+    STORE_RETRY_BASE_DELAY_MS, STORE_RETRY_MAX_ATTEMPTS, STORE_RETRY_MAX_DELAY_MS,
+};
+
+/// How often and how patiently storage operations are retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub max_attempts: u32,
+    /// Upper bound of the delay before the first retry, doubled for every further retry
+    pub base_delay: Duration,
+    /// Upper bound of any delay
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            max_attempts: *STORE_RETRY_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(*STORE_RETRY_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(*STORE_RETRY_MAX_DELAY_MS),
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 1), with full jitter so
+    /// concurrent failures don't retry in lockstep
+    fn delay(&self, retry: u32) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+        cap.mul_f64(fastrand::f64())
+    }
+
+    /// Run `op` until it succeeds, fails with an error that isn't retryable,
+    /// or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, what: impl Display, mut op: F) -> Result<T, StoreError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, StoreError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Err(err) if err.is_retryable() && attempt < self.max_attempts => {
+                    let delay = self.delay(attempt);
+                    eprintln!(
+                        "{what} failed on attempt {attempt}/{}, retrying in {}ms: {err}",
+                        self.max_attempts,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A store whose operations are retried according to a [`RetryPolicy`].
+///
+/// Uploads are passed through as is, because a streamed body can't be sent
+/// twice. [`super::Tier::put_bytes`] retries uploads of buffered bodies.
+pub struct Retrying {
+    inner: Arc<dyn ObjectStore>,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub fn new(inner: Arc<dyn ObjectStore>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    fn what(&self, op: &str, key: &str) -> String {
+        format!("{op} of {key} on {}", self.inner.name())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for Retrying {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn expires_objects(&self) -> bool {
+        self.inner.expires_objects()
+    }
+
+    fn bucket(&self) -> Bucket {
+        self.inner.bucket()
+    }
+
+    async fn put(&self, key: &str, body: ByteStream, opts: PutOptions) -> Result<(), StoreError> {
+        self.inner.put(key, body, opts).await
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StoreError> {
+        self.policy
+            .run(self.what("get", key), || self.inner.get(key))
+            .await
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        self.policy
+            .run(self.what("head", key), || self.inner.head(key))
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.policy
+            .run(self.what("delete", key), || self.inner.delete(key))
+            .await
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<Vec<(String, StoreError)>, StoreError> {
+        let what = format!(
+            "deletion of {} objects on {}",
+            keys.len(),
+            self.inner.name()
+        );
+        let failed = self
+            .policy
+            .run(what, || self.inner.delete_many(keys))
+            .await?;
+
+        // Objects that failed on their own are retried one by one
+        let mut still_failed = vec![];
+        for (key, err) in failed {
+            if !err.is_retryable() {
+                still_failed.push((key, err));
+                continue;
+            }
+            match self.delete(&key).await {
+                Ok(()) | Err(StoreError::NotFound(_)) => {}
+                Err(err) => still_failed.push((key, err)),
+            }
+        }
+        Ok(still_failed)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError> {
+        self.policy
+            .run(self.what("list", prefix), || self.inner.list(prefix))
+            .await
+    }
+
+    async fn list_after(
+        &self,
+        prefix: &str,
+        start_after: &str,
+    ) -> Result<Vec<ObjectInfo>, StoreError> {
+        self.policy
+            .run(self.what("list", prefix), || {
+                self.inner.list_after(prefix, start_after)
+            })
+            .await
+    }
+
+    async fn copy(&self, src: &Bucket, key: &str) -> Result<(), StoreError> {
+        self.policy
+            .run(self.what("copy", key), || self.inner.copy(src, key))
+            .await
+    }
+
+    async fn set_metadata(&self, key: &str, opts: PutOptions) -> Result<(), StoreError> {
+        self.policy
+            .run(self.what("metadata update", key), || {
+                self.inner.set_metadata(key, opts.clone())
+            })
+            .await
+    }
+
+    async fn attach_checksum(
+        &self,
+        key: &str,
+        checksum: &Checksum,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        self.policy
+            .run(self.what("checksum update", key), || {
+                self.inner.attach_checksum(key, checksum, opts.clone())
+            })
+            .await
+    }
+}
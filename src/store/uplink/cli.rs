@@ -0,0 +1,197 @@
+use std::fmt;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+use crate::store::StoreError;
+
+/// Longest stretch of stderr kept in an error
+const MAX_STDERR_LEN: usize = 2048;
+
+/// Why an uplink command failed, as far as its stderr tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UplinkFailure {
+    /// The access grant is malformed, revoked or lacks the permission
+    InvalidAccess,
+    BucketNotFound,
+    ObjectNotFound,
+    /// A satellite or storage node didn't answer in time
+    Timeout,
+    RateLimited,
+    /// uplink succeeded, but printed something we couldn't parse
+    UnexpectedOutput,
+    Other,
+}
+
+impl UplinkFailure {
+    /// Classify a failure by the messages uplink prints for it
+    fn classify(stderr: &str) -> Self {
+        let stderr = stderr.to_lowercase();
+        let mentions = |needles: &[&str]| needles.iter().any(|needle| stderr.contains(needle));
+
+        if mentions(&["object not found"]) {
+            UplinkFailure::ObjectNotFound
+        } else if mentions(&["bucket not found"]) {
+            UplinkFailure::BucketNotFound
+        } else if mentions(&[
+            "invalid access grant",
+            "access grant",
+            "unauthorized",
+            "permission denied",
+            "macaroon",
+        ]) {
+            UplinkFailure::InvalidAccess
+        } else if mentions(&["too many requests", "rate limit"]) {
+            UplinkFailure::RateLimited
+        } else if mentions(&["timeout", "timed out", "deadline exceeded"]) {
+            UplinkFailure::Timeout
+        } else {
+            UplinkFailure::Other
+        }
+    }
+
+    /// Whether running the command again may succeed
+    pub fn is_retryable(self) -> bool {
+        match self {
+            UplinkFailure::InvalidAccess
+            | UplinkFailure::BucketNotFound
+            | UplinkFailure::ObjectNotFound => false,
+            UplinkFailure::Timeout
+            | UplinkFailure::RateLimited
+            | UplinkFailure::UnexpectedOutput
+            | UplinkFailure::Other => true,
+        }
+    }
+}
+
+impl fmt::Display for UplinkFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UplinkFailure::InvalidAccess => "invalid access grant",
+            UplinkFailure::BucketNotFound => "bucket not found",
+            UplinkFailure::ObjectNotFound => "object not found",
+            UplinkFailure::Timeout => "timed out",
+            UplinkFailure::RateLimited => "rate limited",
+            UplinkFailure::UnexpectedOutput => "unexpected output",
+            UplinkFailure::Other => "failed",
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("uplink {op} of {key} on {backend}: {failure}: {detail}")]
+pub struct UplinkError {
+    pub failure: UplinkFailure,
+    pub backend: &'static str,
+    /// The uplink subcommand, e.g. `cp`
+    pub op: &'static str,
+    pub key: String,
+    /// Exit status and stderr of the command
+    pub detail: String,
+}
+
+/// Runs uplink commands against one bucket, turning failures into classified
+/// errors that carry uplink's stderr
+#[derive(Clone)]
+pub struct Runner {
+    backend: &'static str,
+    grant: String,
+}
+
+impl Runner {
+    pub fn new(backend: &'static str, grant: String) -> Self {
+        Self { backend, grant }
+    }
+
+    /// The access grant, which identifies the account the bucket belongs to
+    pub fn grant(&self) -> &str {
+        &self.grant
+    }
+
+    /// `uplink <op>` authenticated with the access grant of the bucket
+    pub fn command(&self, op: &str) -> Command {
+        let mut cmd = Command::new("uplink");
+        cmd.args([op, "--access", self.grant.as_str()]);
+        cmd
+    }
+
+    /// Run a command that doesn't need stdin and return its stdout
+    pub async fn output(
+        &self,
+        op: &'static str,
+        key: &str,
+        mut cmd: Command,
+    ) -> Result<Vec<u8>, StoreError> {
+        let output = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        self.check(op, key, output.status, &output.stderr)?;
+        Ok(output.stdout)
+    }
+
+    /// Turn the exit of a command into an error if it failed. Failures other
+    /// than a missing object are logged, as callers rarely see the details.
+    pub fn check(
+        &self,
+        op: &'static str,
+        key: &str,
+        status: ExitStatus,
+        stderr: &[u8],
+    ) -> Result<(), UplinkError> {
+        if status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(stderr);
+        let stderr = stderr.trim();
+        let stderr = match stderr.char_indices().nth(MAX_STDERR_LEN) {
+            Some((end, _)) => &stderr[..end],
+            None => stderr,
+        };
+
+        let err = self.error(
+            op,
+            key,
+            UplinkFailure::classify(stderr),
+            format!("{status}: {stderr}"),
+        );
+        if err.failure != UplinkFailure::ObjectNotFound {
+            eprintln!("{err}");
+        }
+        Err(err)
+    }
+
+    pub fn error(
+        &self,
+        op: &'static str,
+        key: &str,
+        failure: UplinkFailure,
+        detail: String,
+    ) -> UplinkError {
+        UplinkError {
+            failure,
+            backend: self.backend,
+            op,
+            key: key.to_string(),
+            detail,
+        }
+    }
+}
+
+/// Collect the stderr of a spawned child in the background, so it can't fill
+/// up the pipe while we are busy with stdin or stdout
+pub fn capture_stderr(child: &mut Child) -> JoinHandle<Vec<u8>> {
+    let stderr = child.stderr.take();
+    tokio::spawn(async move {
+        let mut buf = vec![];
+        if let Some(mut stderr) = stderr {
+            stderr.read_to_end(&mut buf).await.ok();
+        }
+        buf
+    })
+}
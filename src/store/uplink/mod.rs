@@ -0,0 +1,426 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use super::integrity::{SHA256_METADATA_KEY, SIZE_METADATA_KEY};
+use super::{Bucket, ByteStream, Checksum, ObjectInfo, ObjectStore, PutOptions, StoreError};
+use crate::consts::{ACCESS_GRANT_NSFW, ACCESS_GRANT_SFW, YRAL_NSFW_VIDEOS, YRAL_VIDEOS};
+
+pub mod cli;
+
+use cli::Runner;
+pub use cli::{UplinkError, UplinkFailure};
+
+/// Size of the chunks read from uplink's stdout when downloading
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A storj bucket accessed by shelling out to the uplink cli
+#[derive(Clone)]
+pub struct UplinkStore {
+    name: &'static str,
+    bucket: String,
+    uplink: Runner,
+}
+
+#[derive(Deserialize)]
+struct LsEntry {
+    kind: String,
+    key: String,
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+impl UplinkStore {
+    pub fn new(name: &'static str, bucket: String, grant: String) -> Self {
+        Self {
+            name,
+            bucket,
+            uplink: Runner::new(name, grant),
+        }
+    }
+
+    pub fn sfw() -> Self {
+        Self::new("storj_sfw", YRAL_VIDEOS.clone(), ACCESS_GRANT_SFW.clone())
+    }
+
+    pub fn nsfw() -> Self {
+        Self::new(
+            "storj_nsfw",
+            YRAL_NSFW_VIDEOS.clone(),
+            ACCESS_GRANT_NSFW.clone(),
+        )
+    }
+
+    fn location(&self, key: &str) -> String {
+        format!("sj://{}/{key}", self.bucket)
+    }
+
+    fn cp(&self) -> Command {
+        let mut cmd = self.uplink.command("cp");
+        cmd.args([
+            "--interactive=false",
+            "--analytics=false",
+            "--progress=false",
+        ]);
+        cmd
+    }
+
+    async fn ls(&self, key: &str, recursive: bool) -> Result<Vec<LsEntry>, StoreError> {
+        let mut cmd = self.uplink.command("ls");
+        cmd.arg("--output=json");
+        if recursive {
+            cmd.arg("--recursive");
+        }
+        cmd.arg(self.location(key));
+
+        let stdout = self.uplink.output("ls", key, cmd).await?;
+
+        String::from_utf8_lossy(&stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line).map_err(|e| {
+                    self.uplink
+                        .error(
+                            "ls",
+                            key,
+                            UplinkFailure::UnexpectedOutput,
+                            format!("couldn't parse {line:?}: {e}"),
+                        )
+                        .into()
+                })
+            })
+            .collect()
+    }
+
+    /// Metadata of an object, `None` if it doesn't exist
+    async fn meta(&self, key: &str) -> Result<Option<BTreeMap<String, String>>, StoreError> {
+        let mut cmd = self.uplink.command("meta");
+        cmd.args(["get", &self.location(key)]);
+        match self.uplink.output("meta", key, cmd).await {
+            Ok(stdout) if stdout.iter().all(u8::is_ascii_whitespace) => Ok(Some(BTreeMap::new())),
+            Ok(stdout) => serde_json::from_slice(&stdout).map(Some).map_err(|e| {
+                self.uplink
+                    .error(
+                        "meta",
+                        key,
+                        UplinkFailure::UnexpectedOutput,
+                        format!("couldn't parse metadata: {e}"),
+                    )
+                    .into()
+            }),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Metadata key holding the content type, as storj objects have none of their own
+const CONTENT_TYPE_METADATA_KEY: &str = "content-type";
+
+/// Suffix of the empty object carrying the checksum of the object it is named
+/// after in its metadata, for checksums only known once the object was written
+const CHECKSUM_SIDECAR_SUFFIX: &str = ".checksum";
+
+fn checksum_sidecar(key: &str) -> String {
+    format!("{key}{CHECKSUM_SIDECAR_SUFFIX}")
+}
+
+/// Flags of `uplink cp` that set the metadata and expiry of the destination
+fn object_flags(opts: &PutOptions) -> Vec<String> {
+    let mut flags = vec![];
+    let mut metadata = opts.metadata.clone();
+    if let Some(content_type) = &opts.content_type {
+        metadata.insert(CONTENT_TYPE_METADATA_KEY.into(), content_type.clone());
+    }
+    if !metadata.is_empty() {
+        let metadata_str = serde_json::to_string(&metadata)
+            .expect("serialization to go through as we are guaranteed utf-8");
+        flags.push(format!("--metadata={metadata_str}"));
+    }
+    if let Some(ttl) = opts.ttl {
+        flags.push("--expires".into());
+        flags.push(format!("+{}s", ttl.as_secs()));
+    }
+    flags
+}
+
+fn is_not_found(err: &StoreError) -> bool {
+    matches!(err, StoreError::Uplink(err) if err.failure == UplinkFailure::ObjectNotFound)
+}
+
+#[async_trait]
+impl ObjectStore for UplinkStore {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn expires_objects(&self) -> bool {
+        true
+    }
+
+    fn bucket(&self) -> Bucket {
+        Bucket {
+            account: self.uplink.grant().to_string(),
+            name: self.bucket.clone(),
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        mut body: ByteStream,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        // Declared before the child so that, if this future is dropped midway,
+        // uplink is killed before its stdin closes. Seeing EOF, it would
+        // commit a truncated object.
+        let mut pipe;
+        let mut child = self
+            .cp()
+            .args(object_flags(&opts))
+            .args(["-", self.location(key).as_str()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stderr = cli::capture_stderr(&mut child);
+
+        pipe = child.stdin.take().expect("Stdin pipe to be opened for us");
+        let written = async {
+            while let Some(chunk) = body.next().await {
+                pipe.write_all(&chunk?).await?;
+            }
+            pipe.flush().await
+        }
+        .await;
+        if let Err(err) = written {
+            // Kill uplink before it sees EOF, otherwise it commits a truncated object
+            child.kill().await.ok();
+            return Err(err.into());
+        }
+        drop(pipe); // Close stdin to signal EOF
+
+        // Once uplink exited, dropping the child doesn't kill anything anymore
+        let status = child.wait().await?;
+        let stderr = stderr.await.unwrap_or_default();
+        self.uplink.check("cp", key, status, &stderr)?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StoreError> {
+        let mut child = self
+            .cp()
+            .args([self.location(key).as_str(), "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Stops the download if the task reading it is dropped, e.g. on shutdown
+            .kill_on_drop(true)
+            .spawn()?;
+        let stderr = cli::capture_stderr(&mut child);
+
+        let mut stdout = child
+            .stdout
+            .take()
+            .expect("Stdout pipe to be opened for us");
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let uplink = self.uplink.clone();
+        let key = key.to_string();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; READ_CHUNK_SIZE];
+            loop {
+                match stdout.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx
+                            .send(Ok(Bytes::copy_from_slice(&buf[..n])))
+                            .await
+                            .is_err()
+                        {
+                            // Reader went away, no point in downloading the rest
+                            child.kill().await.ok();
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        tx.send(Err(e)).await.ok();
+                        child.kill().await.ok();
+                        return;
+                    }
+                }
+            }
+
+            match child.wait().await {
+                Ok(status) => {
+                    let stderr = stderr.await.unwrap_or_default();
+                    if let Err(err) = uplink.check("cp", &key, status, &stderr) {
+                        tx.send(Err(std::io::Error::other(err))).await.ok();
+                    }
+                }
+                Err(e) => {
+                    tx.send(Err(e)).await.ok();
+                }
+            }
+        });
+
+        Ok(Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        let entries = match self.ls(key, false).await {
+            Ok(entries) => entries,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // The listing is by prefix, so it also tells whether there is a sidecar
+        let is_object = |entry: &LsEntry, key: &str| {
+            let file_name = key.rsplit('/').next().unwrap_or(key);
+            entry.kind == "OBJ" && (entry.key == file_name || entry.key == key)
+        };
+        let Some(entry) = entries.iter().find(|entry| is_object(entry, key)) else {
+            return Ok(None);
+        };
+        let sidecar = checksum_sidecar(key);
+        let has_sidecar = entries.iter().any(|entry| is_object(entry, &sidecar));
+
+        let Some(mut metadata) = self.meta(key).await? else {
+            return Ok(None);
+        };
+        if has_sidecar && !metadata.contains_key(SHA256_METADATA_KEY) {
+            let sidecar = self.meta(&sidecar).await?.unwrap_or_default();
+            for field in [SHA256_METADATA_KEY, SIZE_METADATA_KEY] {
+                if let Some(value) = sidecar.get(field) {
+                    metadata.insert(field.into(), value.clone());
+                }
+            }
+        }
+
+        Ok(Some(ObjectInfo {
+            key: key.to_string(),
+            size: entry.size,
+            content_type: metadata.remove(CONTENT_TYPE_METADATA_KEY),
+            metadata,
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let remove = |key: String| async move {
+            let mut cmd = self.uplink.command("rm");
+            cmd.arg(self.location(&key));
+            match self.uplink.output("rm", &key, cmd).await {
+                Err(err) if is_not_found(&err) => Err(StoreError::NotFound(key)),
+                result => result.map(|_| ()),
+            }
+        };
+
+        // The sidecar goes along with the object, most objects have none
+        let (removed, sidecar_removed) =
+            tokio::join!(remove(key.to_string()), remove(checksum_sidecar(key)));
+        match sidecar_removed {
+            Ok(()) | Err(StoreError::NotFound(_)) => {}
+            Err(e) => eprintln!("{}: couldn't delete the checksum of {key}: {e}", self.name),
+        }
+        removed
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StoreError> {
+        // uplink reports keys relative to the listed "directory"
+        let dir = match prefix.rfind('/') {
+            Some(idx) => &prefix[..=idx],
+            None => "",
+        };
+
+        let entries = match self.ls(prefix, true).await {
+            Ok(entries) => entries,
+            Err(err) if is_not_found(&err) => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.kind == "OBJ")
+            .map(|entry| ObjectInfo {
+                key: format!("{dir}{}", entry.key),
+                size: entry.size,
+                content_type: None,
+                metadata: BTreeMap::new(),
+            })
+            .filter(|info| {
+                info.key.starts_with(prefix) && !info.key.ends_with(CHECKSUM_SIDECAR_SUFFIX)
+            })
+            .collect())
+    }
+
+    async fn copy(&self, src: &Bucket, key: &str) -> Result<(), StoreError> {
+        // Copying between two remote locations happens server side
+        let copy = |key: String| async move {
+            let mut cmd = self.cp();
+            cmd.args([format!("sj://{}/{key}", src.name), self.location(&key)]);
+            match self.uplink.output("cp", &key, cmd).await {
+                Err(err) if is_not_found(&err) => Err(StoreError::NotFound(key)),
+                result => result.map(|_| ()),
+            }
+        };
+
+        copy(key.to_string()).await?;
+        // The checksum sidecar goes along with the object, most objects have none
+        match copy(checksum_sidecar(key)).await {
+            Ok(()) | Err(StoreError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_metadata(&self, key: &str, opts: PutOptions) -> Result<(), StoreError> {
+        // uplink can't change the metadata or expiry of an object, and a server
+        // side copy keeps those of the original. So the object is streamed
+        // through the service into a staged copy carrying the new ones, which
+        // then replaces the original. Writing over the original directly would
+        // break the download it is read from.
+        let tmp = super::staging_key();
+        let body = self.get(key).await?;
+        self.put(&tmp, body, opts).await?;
+
+        let mut cmd = self.uplink.command("mv");
+        cmd.args([self.location(&tmp), self.location(key)]);
+        if let Err(err) = self.uplink.output("mv", key, cmd).await {
+            if let Err(e) = self.delete(&tmp).await {
+                eprintln!("{}: couldn't delete {tmp}: {e}", self.name);
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    async fn attach_checksum(
+        &self,
+        key: &str,
+        checksum: &Checksum,
+        opts: PutOptions,
+    ) -> Result<(), StoreError> {
+        // Adding it to the object's own metadata would rewrite the whole object
+        // (see `set_metadata`). It goes into the metadata of an empty sidecar
+        // expiring along with the object instead, which `head` merges in.
+        let mut metadata = BTreeMap::new();
+        checksum.apply(&mut metadata);
+        let sidecar_opts = PutOptions::default()
+            .with_metadata(metadata)
+            .with_ttl(opts.ttl)
+            .with_content_length(Some(0));
+        self.put(
+            &checksum_sidecar(key),
+            super::once(Bytes::new()),
+            sidecar_opts,
+        )
+        .await
+    }
+}
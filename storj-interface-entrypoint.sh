@@ -6,7 +6,8 @@ if [ -n "$CREDENTIALS_DIRECTORY" ] && [ -d "$CREDENTIALS_DIRECTORY" ]; then
     for cred in "$CREDENTIALS_DIRECTORY"/*; do
         if [ -f "$cred" ]; then
             secret_name=$(basename "$cred")
-            declare -x $secret_name=$(cat "$cred")
+            # quoted, as e.g. the json of API_KEYS contains spaces
+            declare -x "$secret_name=$(cat "$cred")"
             echo "  $secret_name"
         fi
     done